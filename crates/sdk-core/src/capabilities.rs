@@ -0,0 +1,389 @@
+//! Enforcement of the capabilities advertised by a connector.
+//!
+//! The SDK checks each incoming request against the connector's
+//! [`Connector::get_capabilities`](crate::connector::Connector::get_capabilities) before the
+//! connector is called, so that requests which use unsupported features are rejected with an
+//! `UnsupportedOperation` error (501) rather than reaching the connector.
+
+use ndc_models as models;
+
+use crate::connector::{MutationError, QueryError};
+
+/// Check that a query request only uses features the connector supports.
+pub fn check_query_capabilities(
+    capabilities: &models::Capabilities,
+    request: &models::QueryRequest,
+) -> Result<(), QueryError> {
+    let checker = CapabilityChecker { capabilities };
+    require(
+        request.variables.is_none() || capabilities.query.variables.is_some(),
+        "query.variables",
+    )
+    .and_then(|()| checker.query(&request.query))
+    .map_err(unsupported_query)
+}
+
+/// Check that a query can be explained, and only uses features the connector supports.
+pub fn check_query_explain_capabilities(
+    capabilities: &models::Capabilities,
+    request: &models::QueryRequest,
+) -> Result<(), QueryError> {
+    require(capabilities.query.explain.is_some(), "query.explain").map_err(unsupported_query)?;
+    check_query_capabilities(capabilities, request)
+}
+
+/// Check that a mutation request only uses features the connector supports.
+pub fn check_mutation_capabilities(
+    capabilities: &models::Capabilities,
+    request: &models::MutationRequest,
+) -> Result<(), MutationError> {
+    let checker = CapabilityChecker { capabilities };
+    request
+        .operations
+        .iter()
+        .try_for_each(|operation| match operation {
+            models::MutationOperation::Procedure { fields, .. } => fields
+                .as_ref()
+                .map_or(Ok(()), |fields| checker.nested_field(fields)),
+        })
+        .map_err(unsupported_mutation)
+}
+
+/// Check that a mutation can be explained, and only uses features the connector supports.
+pub fn check_mutation_explain_capabilities(
+    capabilities: &models::Capabilities,
+    request: &models::MutationRequest,
+) -> Result<(), MutationError> {
+    require(capabilities.mutation.explain.is_some(), "mutation.explain")
+        .map_err(unsupported_mutation)?;
+    check_mutation_capabilities(capabilities, request)
+}
+
+/// The name of an unsupported capability, as a path into the capabilities response.
+type Unsupported = &'static str;
+
+fn require(supported: bool, capability: Unsupported) -> Result<(), Unsupported> {
+    if supported {
+        Ok(())
+    } else {
+        Err(capability)
+    }
+}
+
+fn unsupported_query(capability: Unsupported) -> QueryError {
+    QueryError::new_unsupported_operation(&format!("capability '{capability}' is not supported"))
+        .with_details(serde_json::json!({ "capability": capability }))
+}
+
+fn unsupported_mutation(capability: Unsupported) -> MutationError {
+    MutationError::new_unsupported_operation(&format!("capability '{capability}' is not supported"))
+        .with_details(serde_json::json!({ "capability": capability }))
+}
+
+struct CapabilityChecker<'a> {
+    capabilities: &'a models::Capabilities,
+}
+
+impl CapabilityChecker<'_> {
+    fn aggregates(&self) -> Option<&models::AggregateCapabilities> {
+        self.capabilities.query.aggregates.as_ref()
+    }
+
+    fn relationships(&self) -> Option<&models::RelationshipCapabilities> {
+        self.capabilities.relationships.as_ref()
+    }
+
+    fn nested_fields(&self) -> &models::NestedFieldCapabilities {
+        &self.capabilities.query.nested_fields
+    }
+
+    fn query(&self, query: &models::Query) -> Result<(), Unsupported> {
+        if let Some(aggregates) = &query.aggregates {
+            if !aggregates.is_empty() {
+                require(self.aggregates().is_some(), "query.aggregates")?;
+            }
+            for aggregate in aggregates.values() {
+                self.aggregate(aggregate)?;
+            }
+        }
+        if query.groups.is_some() {
+            require(self.aggregates().is_some(), "query.aggregates")?;
+            require(
+                self.aggregates()
+                    .is_some_and(|aggregates| aggregates.group_by.is_some()),
+                "query.aggregates.group_by",
+            )?;
+        }
+        if let Some(fields) = &query.fields {
+            for field in fields.values() {
+                self.field(field)?;
+            }
+        }
+        if let Some(order_by) = &query.order_by {
+            for element in &order_by.elements {
+                self.order_by_target(&element.target)?;
+            }
+        }
+        if let Some(predicate) = &query.predicate {
+            self.expression(predicate)?;
+        }
+        Ok(())
+    }
+
+    fn field(&self, field: &models::Field) -> Result<(), Unsupported> {
+        match field {
+            models::Field::Column { fields, .. } => fields
+                .as_ref()
+                .map_or(Ok(()), |fields| self.nested_field(fields)),
+            models::Field::Relationship { query, .. } => {
+                require(self.relationships().is_some(), "relationships")?;
+                self.query(query)
+            }
+        }
+    }
+
+    fn nested_field(&self, nested_field: &models::NestedField) -> Result<(), Unsupported> {
+        match nested_field {
+            models::NestedField::Object(object) => object
+                .fields
+                .values()
+                .try_for_each(|field| self.field(field)),
+            models::NestedField::Array(array) => self.nested_field(&array.fields),
+            models::NestedField::Collection(collection) => {
+                require(
+                    self.nested_fields().nested_collections.is_some(),
+                    "query.nested_fields.nested_collections",
+                )?;
+                self.query(&collection.query)
+            }
+        }
+    }
+
+    fn aggregate(&self, aggregate: &models::Aggregate) -> Result<(), Unsupported> {
+        match aggregate {
+            models::Aggregate::ColumnCount { field_path, .. }
+            | models::Aggregate::SingleColumn { field_path, .. } => require(
+                is_empty(field_path.as_deref()) || self.nested_fields().aggregates.is_some(),
+                "query.nested_fields.aggregates",
+            ),
+            models::Aggregate::StarCount {} => Ok(()),
+        }
+    }
+
+    fn order_by_target(&self, target: &models::OrderByTarget) -> Result<(), Unsupported> {
+        match target {
+            models::OrderByTarget::Column {
+                field_path, path, ..
+            } => {
+                require(
+                    is_empty(field_path.as_deref()) || self.nested_fields().order_by.is_some(),
+                    "query.nested_fields.order_by",
+                )?;
+                self.path(path)
+            }
+            models::OrderByTarget::Aggregate { aggregate, path } => {
+                require(self.aggregates().is_some(), "query.aggregates")?;
+                if !path.is_empty() {
+                    require(
+                        self.relationships().is_some_and(|relationships| {
+                            relationships.order_by_aggregate.is_some()
+                        }),
+                        "relationships.order_by_aggregate",
+                    )?;
+                }
+                self.aggregate(aggregate)?;
+                self.path(path)
+            }
+        }
+    }
+
+    fn path(&self, path: &[models::PathElement]) -> Result<(), Unsupported> {
+        if !path.is_empty() {
+            require(self.relationships().is_some(), "relationships")?;
+        }
+        for element in path {
+            if let Some(predicate) = &element.predicate {
+                self.expression(predicate)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn expression(&self, expression: &models::Expression) -> Result<(), Unsupported> {
+        match expression {
+            models::Expression::And { expressions } | models::Expression::Or { expressions } => {
+                expressions
+                    .iter()
+                    .try_for_each(|expression| self.expression(expression))
+            }
+            models::Expression::Not { expression } => self.expression(expression),
+            models::Expression::UnaryComparisonOperator { column, .. }
+            | models::Expression::ArrayComparison { column, .. } => self.comparison_target(column),
+            models::Expression::BinaryComparisonOperator { column, value, .. } => {
+                self.comparison_target(column)?;
+                self.comparison_value(value)
+            }
+            models::Expression::Exists {
+                in_collection,
+                predicate,
+            } => {
+                self.exists_in_collection(in_collection)?;
+                predicate
+                    .as_ref()
+                    .map_or(Ok(()), |predicate| self.expression(predicate))
+            }
+        }
+    }
+
+    fn comparison_target(&self, target: &models::ComparisonTarget) -> Result<(), Unsupported> {
+        match target {
+            models::ComparisonTarget::Column { field_path, .. } => require(
+                is_empty(field_path.as_deref()) || self.nested_fields().filter_by.is_some(),
+                "query.nested_fields.filter_by",
+            ),
+            models::ComparisonTarget::Aggregate { aggregate, path } => {
+                require(
+                    self.aggregates()
+                        .is_some_and(|aggregates| aggregates.filter_by.is_some()),
+                    "query.aggregates.filter_by",
+                )?;
+                self.aggregate(aggregate)?;
+                self.path(path)
+            }
+        }
+    }
+
+    fn comparison_value(&self, value: &models::ComparisonValue) -> Result<(), Unsupported> {
+        match value {
+            models::ComparisonValue::Column { path, scope, .. } => {
+                require(
+                    scope.unwrap_or(0) == 0
+                        || self.capabilities.query.exists.named_scopes.is_some(),
+                    "query.exists.named_scopes",
+                )?;
+                if !path.is_empty() {
+                    self.relation_comparisons()?;
+                }
+                self.path(path)
+            }
+            models::ComparisonValue::Scalar { .. } | models::ComparisonValue::Variable { .. } => {
+                Ok(())
+            }
+        }
+    }
+
+    fn exists_in_collection(
+        &self,
+        in_collection: &models::ExistsInCollection,
+    ) -> Result<(), Unsupported> {
+        match in_collection {
+            models::ExistsInCollection::Related { .. } => self.relation_comparisons(),
+            models::ExistsInCollection::Unrelated { .. } => require(
+                self.capabilities.query.exists.unrelated.is_some(),
+                "query.exists.unrelated",
+            ),
+            models::ExistsInCollection::NestedCollection { .. } => require(
+                self.capabilities.query.exists.nested_collections.is_some(),
+                "query.exists.nested_collections",
+            ),
+            models::ExistsInCollection::NestedScalarCollection { .. } => require(
+                self.capabilities
+                    .query
+                    .exists
+                    .nested_scalar_collections
+                    .is_some(),
+                "query.exists.nested_scalar_collections",
+            ),
+        }
+    }
+
+    fn relation_comparisons(&self) -> Result<(), Unsupported> {
+        require(self.relationships().is_some(), "relationships")?;
+        require(
+            self.relationships()
+                .is_some_and(|relationships| relationships.relation_comparisons.is_some()),
+            "relationships.relation_comparisons",
+        )
+    }
+}
+
+fn is_empty(field_path: Option<&[models::FieldName]>) -> bool {
+    matches!(field_path, None | Some([]))
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_models as models;
+    use serde_json::json;
+
+    use crate::connector::example::Example;
+    use crate::connector::{Connector, QueryError};
+
+    use super::check_query_capabilities;
+
+    fn request(query: &serde_json::Value, variables: bool) -> anyhow::Result<models::QueryRequest> {
+        Ok(serde_json::from_value(json!({
+            "collection": "articles",
+            "arguments": {},
+            "query": query,
+            "collection_relationships": {},
+            "variables": if variables { json!([{}]) } else { json!(null) }
+        }))?)
+    }
+
+    fn unsupported_capability(result: Result<(), QueryError>) -> serde_json::Value {
+        match result {
+            Err(QueryError::UnsupportedOperation(err)) => err.details["capability"].clone(),
+            other => panic!("expected an unsupported operation error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_a_request_using_only_supported_features() -> anyhow::Result<()> {
+        let request = request(
+            &json!({ "fields": { "title": { "type": "column", "column": "title" } } }),
+            false,
+        )?;
+
+        assert!(check_query_capabilities(&Example::get_capabilities().await, &request).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_variables_when_unsupported() -> anyhow::Result<()> {
+        let request = request(&json!({}), true)?;
+
+        let capability = unsupported_capability(check_query_capabilities(
+            &Example::get_capabilities().await,
+            &request,
+        ));
+
+        assert_eq!(capability, json!("query.variables"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_relationships_when_unsupported() -> anyhow::Result<()> {
+        let request = request(
+            &json!({
+                "fields": {
+                    "author": {
+                        "type": "relationship",
+                        "relationship": "article_author",
+                        "arguments": {},
+                        "query": {}
+                    }
+                }
+            }),
+            false,
+        )?;
+
+        let capability = unsupported_capability(check_query_capabilities(
+            &Example::get_capabilities().await,
+            &request,
+        ));
+
+        assert_eq!(capability, json!("relationships"));
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::json_response::JsonResponse;
+use crate::request_context::RequestContext;
 use async_trait::async_trait;
 use ndc_models as models;
 use std::path::Path;
+use std::time::Duration;
 pub mod error;
 pub mod example;
 pub use error::*;
@@ -16,6 +18,10 @@ pub use error::*;
 /// (if any), and provides any necessary context for observability purposes
 /// (metrics, logging and tracing).
 ///
+/// Methods which handle a request are also given a [`RequestContext`], which
+/// describes the incoming HTTP request: its headers, a request ID, the
+/// requested NDC version, and its tracing span.
+///
 /// ## Configuration
 ///
 /// Connectors encapsulate data sources, and likely require configuration
@@ -51,7 +57,26 @@ pub trait Connector: Send + 'static {
     /// query metrics which cannot be updated directly, e.g.
     /// the number of idle connections in a connection pool
     /// can be polled but not updated directly.
-    fn fetch_metrics(configuration: &Self::Configuration, state: &Self::State) -> Result<()>;
+    ///
+    /// This must not block. Connectors which need to wait, for example to query an upstream
+    /// service, should implement [`Connector::fetch_metrics_async`] instead.
+    fn fetch_metrics(_configuration: &Self::Configuration, _state: &Self::State) -> Result<()> {
+        Ok(())
+    }
+
+    /// Update any metrics from the state, asynchronously.
+    ///
+    /// This is called before the metrics are gathered for each request to `/metrics`. If it does
+    /// not complete within the server's metrics timeout, it is cancelled and the metrics are
+    /// served with their previous values.
+    ///
+    /// The default implementation calls [`Connector::fetch_metrics`].
+    async fn fetch_metrics_async(
+        configuration: &Self::Configuration,
+        state: &Self::State,
+    ) -> Result<()> {
+        Self::fetch_metrics(configuration, state)
+    }
 
     /// Check the health of the connector.
     ///
@@ -66,6 +91,20 @@ pub trait Connector: Send + 'static {
         Ok(())
     }
 
+    /// Check that the connector can reach the external services it depends on.
+    ///
+    /// Unlike [`Connector::get_health_readiness`], this may make requests to external data
+    /// sources, for example to check that a database connection can be acquired. It is used by
+    /// the `/health/connected` endpoint, and should return promptly.
+    ///
+    /// The default implementation performs no checks.
+    async fn check_connectivity(
+        _configuration: &Self::Configuration,
+        _state: &Self::State,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     /// Get the connector's capabilities.
     ///
     /// This function implements the [capabilities endpoint](https://hasura.github.io/ndc-spec/specification/capabilities.html)
@@ -90,6 +129,7 @@ pub trait Connector: Send + 'static {
     async fn query_explain(
         configuration: &Self::Configuration,
         state: &Self::State,
+        request_context: &RequestContext,
         request: models::QueryRequest,
     ) -> Result<JsonResponse<models::ExplainResponse>>;
 
@@ -103,6 +143,7 @@ pub trait Connector: Send + 'static {
     async fn mutation_explain(
         configuration: &Self::Configuration,
         state: &Self::State,
+        request_context: &RequestContext,
         request: models::MutationRequest,
     ) -> Result<JsonResponse<models::ExplainResponse>>;
 
@@ -116,9 +157,25 @@ pub trait Connector: Send + 'static {
     async fn mutation(
         configuration: &Self::Configuration,
         state: &Self::State,
+        request_context: &RequestContext,
         request: models::MutationRequest,
     ) -> Result<JsonResponse<models::MutationResponse>>;
 
+    /// How long the results of queries against a collection may be cached by the SDK.
+    ///
+    /// When the server is run with a query cache, responses to queries which only involve
+    /// collections with a TTL are cached for the shortest of their TTLs, keyed by the request and
+    /// the configuration generation. Only opt in collections whose results do not depend on the
+    /// request headers, and which may be served stale for the TTL.
+    ///
+    /// The default implementation caches nothing.
+    fn query_cache_ttl(
+        _configuration: &Self::Configuration,
+        _collection: &models::CollectionName,
+    ) -> Option<Duration> {
+        None
+    }
+
     /// Execute a query
     ///
     /// This function implements the [query endpoint](https://hasura.github.io/ndc-spec/specification/queries/index.html)
@@ -129,6 +186,7 @@ pub trait Connector: Send + 'static {
     async fn query(
         configuration: &Self::Configuration,
         state: &Self::State,
+        request_context: &RequestContext,
         request: models::QueryRequest,
     ) -> Result<JsonResponse<models::QueryResponse>>;
 }
@@ -166,4 +224,26 @@ pub trait ConnectorSetup: Send + Sync + 'static {
         configuration: &<Self::Connector as Connector>::Configuration,
         metrics: &mut prometheus::Registry,
     ) -> Result<<Self::Connector as Connector>::State>;
+
+    /// The name of the connector, which identifies it in the traces, logs and metrics it exports
+    /// unless `OTEL_SERVICE_NAME` is set. This is typically `Some(env!("CARGO_PKG_NAME"))`.
+    ///
+    /// Defaults to the name of the SDK.
+    fn connector_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The version of the connector, which identifies its build in the traces, logs and metrics
+    /// it exports. This is typically `Some(env!("CARGO_PKG_VERSION"))`.
+    ///
+    /// Defaults to the version of the SDK.
+    fn connector_version(&self) -> Option<&str> {
+        None
+    }
+
+    /// Further OpenTelemetry resource attributes describing the connector, such as
+    /// `service.namespace`. Attributes set in `OTEL_RESOURCE_ATTRIBUTES` take precedence.
+    fn resource_attributes(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
 }
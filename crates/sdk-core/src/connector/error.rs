@@ -17,6 +17,7 @@ pub type Result<T> = std::result::Result<T, ErrorResponse>;
 pub struct ErrorResponse {
     status_code: StatusCode,
     inner: ndc_models::ErrorResponse,
+    invalidate_state: bool,
 }
 
 impl ErrorResponse {
@@ -24,6 +25,7 @@ impl ErrorResponse {
         Self {
             status_code,
             inner: ndc_models::ErrorResponse { message, details },
+            invalidate_state: false,
         }
     }
 
@@ -42,6 +44,7 @@ impl ErrorResponse {
                 message: value.to_string(),
                 details: serde_json::Value::Null,
             },
+            invalidate_state: false,
         }
     }
 
@@ -52,6 +55,24 @@ impl ErrorResponse {
             ..self
         }
     }
+
+    /// Mark this error as indicating that the connector state is unusable, for example because a
+    /// connection pool is permanently broken.
+    ///
+    /// When a request fails with such an error, the SDK drops the state, and initializes it again
+    /// on the next request. See [`ServerState::invalidate_state`](crate::state::ServerState::invalidate_state).
+    #[must_use]
+    pub fn with_invalidate_state(self, invalidate_state: bool) -> Self {
+        Self {
+            invalidate_state,
+            ..self
+        }
+    }
+
+    /// Whether this error indicates that the connector state is unusable.
+    pub fn invalidates_state(&self) -> bool {
+        self.invalidate_state
+    }
 }
 
 impl std::fmt::Display for ErrorResponse {
@@ -72,6 +93,7 @@ impl From<Box<dyn std::error::Error + Send + Sync>> for ErrorResponse {
                 message: value.to_string(),
                 details: serde_json::Value::Null,
             },
+            invalidate_state: false,
         }
     }
 }
@@ -81,6 +103,7 @@ impl From<ndc_models::ErrorResponse> for ErrorResponse {
         Self {
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
             inner: value,
+            invalidate_state: false,
         }
     }
 }
@@ -100,6 +123,7 @@ impl From<String> for ErrorResponse {
                 message: value,
                 details: serde_json::Value::Null,
             },
+            invalidate_state: false,
         }
     }
 }
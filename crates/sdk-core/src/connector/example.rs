@@ -88,6 +88,7 @@ impl Connector for Example {
     async fn query_explain(
         _configuration: &Self::Configuration,
         _state: &Self::State,
+        _request_context: &RequestContext,
         _request: models::QueryRequest,
     ) -> Result<JsonResponse<models::ExplainResponse>> {
         todo!()
@@ -96,6 +97,7 @@ impl Connector for Example {
     async fn mutation_explain(
         _configuration: &Self::Configuration,
         _state: &Self::State,
+        _request_context: &RequestContext,
         _request: models::MutationRequest,
     ) -> Result<JsonResponse<models::ExplainResponse>> {
         todo!()
@@ -104,6 +106,7 @@ impl Connector for Example {
     async fn mutation(
         _configuration: &Self::Configuration,
         _state: &Self::State,
+        _request_context: &RequestContext,
         _request: models::MutationRequest,
     ) -> Result<JsonResponse<models::MutationResponse>> {
         todo!()
@@ -112,6 +115,7 @@ impl Connector for Example {
     async fn query(
         _configuration: &Self::Configuration,
         _state: &Self::State,
+        _request_context: &RequestContext,
         _request: models::QueryRequest,
     ) -> Result<JsonResponse<models::QueryResponse>> {
         todo!()
@@ -1,14 +1,26 @@
+use std::hash::Hasher;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 #[cfg(feature = "axum")]
 use axum::response::IntoResponse;
 use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use http::HeaderValue;
 #[cfg(feature = "axum")]
-use http::{header, HeaderValue};
+use http::{header, HeaderMap, StatusCode};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 /// Represents a response value that will be serialized to JSON.
 ///
 /// The value may be of a type that implements `serde::Serialize`, or it may be
 /// a contiguous sequence of bytes, which are _assumed_ to be valid JSON.
-#[derive(Debug, Clone)]
+///
+/// For very large responses, the value may also be a stream of bytes, which is
+/// written to the client as it is produced, so that the whole response does
+/// not need to be held in memory.
+#[derive(Debug)]
 pub enum JsonResponse<A> {
     /// A value that can be serialized to JSON.
     Value(A),
@@ -16,6 +28,10 @@ pub enum JsonResponse<A> {
     /// type `A`. This is not guaranteed by the SDK; the connector is
     /// responsible for ensuring this.
     Serialized(Bytes),
+    /// A stream of bytes which, concatenated, are assumed to represent a
+    /// serialized JSON value of type `A`. As with [`JsonResponse::Serialized`],
+    /// the connector is responsible for ensuring this.
+    Streamed(JsonStream),
 }
 
 impl<A> From<A> for JsonResponse<A> {
@@ -24,17 +40,50 @@ impl<A> From<A> for JsonResponse<A> {
     }
 }
 
+impl<A> JsonResponse<A> {
+    /// Constructs a streamed response from a stream of byte chunks.
+    pub fn from_stream<S, B, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<B, E>> + Send + 'static,
+        B: Into<Bytes> + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        Self::Streamed(JsonStream::new(stream))
+    }
+}
+
 impl<A: (for<'de> serde::Deserialize<'de>)> JsonResponse<A> {
     /// Unwraps the value, deserializing if necessary.
     ///
+    /// Streamed responses are collected in full before being deserialized.
+    ///
     /// This is only intended for testing and compatibility. If it lives on a
     /// critical path, we recommend you avoid it.
-    pub fn into_value<E: From<Box<dyn std::error::Error + Send + Sync>>>(self) -> Result<A, E> {
+    pub async fn into_value<E: From<BoxError>>(self) -> Result<A, E> {
         match self {
             Self::Value(value) => Ok(value),
             Self::Serialized(bytes) => {
                 serde_json::de::from_slice(&bytes).map_err(|err| E::from(Box::new(err)))
             }
+            Self::Streamed(stream) => {
+                let bytes = stream.collect_bytes().await.map_err(E::from)?;
+                serde_json::de::from_slice(&bytes).map_err(|err| E::from(Box::new(err)))
+            }
+        }
+    }
+}
+
+impl<A: serde::Serialize> JsonResponse<A> {
+    /// Serializes the value if necessary, returning the JSON bytestring.
+    ///
+    /// Streamed responses are collected in full.
+    pub async fn into_bytes<E: From<BoxError>>(self) -> Result<Bytes, E> {
+        match self {
+            Self::Value(value) => serde_json::to_vec(&value)
+                .map(Bytes::from)
+                .map_err(|err| E::from(Box::new(err))),
+            Self::Serialized(bytes) => Ok(bytes),
+            Self::Streamed(stream) => stream.collect_bytes().await.map_err(E::from),
         }
     }
 }
@@ -52,17 +101,138 @@ impl<A: serde::Serialize> IntoResponse for JsonResponse<A> {
                 bytes,
             )
                 .into_response(),
+            Self::Streamed(stream) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+                )],
+                axum::body::StreamBody::new(stream),
+            )
+                .into_response(),
         }
     }
 }
 
+/// A serialized JSON response, along with an entity tag derived from its contents.
+///
+/// This is used for responses which rarely change, such as the schema, so that they can be
+/// serialized once and served many times, and so that clients can avoid downloading them again
+/// by sending the entity tag in an `If-None-Match` header.
+#[derive(Clone, Debug)]
+pub struct CachedJsonResponse {
+    bytes: Bytes,
+    etag: HeaderValue,
+}
+
+impl CachedJsonResponse {
+    pub fn new(bytes: Bytes) -> Self {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(&bytes);
+        // the tag is weak, as the same content may be sent with different content encodings
+        let etag = HeaderValue::from_str(&format!("W/\"{:016x}\"", hasher.finish()))
+            .expect("a quoted hexadecimal string is a valid header value");
+        Self { bytes, etag }
+    }
+
+    /// The serialized JSON value.
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    /// The weak entity tag of the response, such as `W/"0123456789abcdef"`.
+    pub fn etag(&self) -> &HeaderValue {
+        &self.etag
+    }
+
+    /// Whether the `If-None-Match` header of a request matches this response, meaning that the
+    /// client already has it.
+    #[cfg(feature = "axum")]
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        let Ok(etag) = self.etag.to_str() else {
+            return false;
+        };
+        let etag = etag.trim_start_matches("W/");
+        request_headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            // `If-None-Match` uses the weak comparison function
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    /// Respond to a request, with a 304 Not Modified if the client already has this response.
+    #[cfg(feature = "axum")]
+    pub fn respond_to(&self, request_headers: &HeaderMap) -> axum::response::Response {
+        if self.matches(request_headers) {
+            (
+                StatusCode::NOT_MODIFIED,
+                [(header::ETAG, self.etag.clone())],
+            )
+                .into_response()
+        } else {
+            (
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+                    ),
+                    (header::ETAG, self.etag.clone()),
+                ],
+                self.bytes.clone(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// A stream of byte chunks making up a serialized JSON value.
+///
+/// See [`JsonResponse::Streamed`].
+pub struct JsonStream(Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send>>);
+
+impl JsonStream {
+    pub fn new<S, B, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<B, E>> + Send + 'static,
+        B: Into<Bytes> + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        Self(Box::pin(stream.map_ok(Into::into).map_err(Into::into)))
+    }
+
+    /// Collects the whole stream into a single contiguous bytestring.
+    pub async fn collect_bytes(mut self) -> Result<Bytes, BoxError> {
+        let mut buffer = Vec::new();
+        while let Some(chunk) = self.try_next().await? {
+            buffer.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(buffer))
+    }
+}
+
+impl Stream for JsonStream {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl std::fmt::Debug for JsonStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JsonStream").finish_non_exhaustive()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use axum::{routing, Router};
     use reqwest::StatusCode;
 
-    use super::test_client::TestClient;
     use super::*;
+    use crate::test_client::TestClient;
 
     #[tokio::test]
     async fn serializes_value_to_json() -> anyhow::Result<()> {
@@ -117,47 +287,91 @@ mod tests {
         Ok(())
     }
 
-    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-    struct Person {
-        name: String,
-        age: u16,
+    #[tokio::test]
+    async fn writes_json_stream_in_chunks() -> anyhow::Result<()> {
+        let app = Router::new().route(
+            "/",
+            routing::get(|| async {
+                JsonResponse::<Person>::from_stream(futures_util::stream::iter([
+                    Ok::<_, std::io::Error>(r#"{"name":"Carol"#),
+                    Ok(r#" Carrot","age":13}"#),
+                ]))
+            }),
+        );
+
+        let client = TestClient::new(app)?;
+        let response = client.get("/").send().await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_all("Content-Type").iter().collect::<Vec<_>>(),
+            vec!["application/json"]
+        );
+        assert_eq!(
+            headers
+                .get_all("Transfer-Encoding")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["chunked"]
+        );
+
+        let body = response.text().await?;
+        assert_eq!(body, r#"{"name":"Carol Carrot","age":13}"#);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-pub mod test_client {
-    use std::net::SocketAddr;
+    #[tokio::test]
+    async fn collects_json_stream_into_value() -> anyhow::Result<()> {
+        let response = JsonResponse::<Person>::from_stream(futures_util::stream::iter([
+            Ok::<_, std::io::Error>(r#"{"name":"Dave"#),
+            Ok(r#" Dumpling","age":99}"#),
+        ]));
 
-    const LOCALHOST: std::net::IpAddr = std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST);
+        let person: Person = response
+            .into_value::<Box<dyn std::error::Error + Send + Sync>>()
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
 
-    pub struct TestClient {
-        address: SocketAddr,
-        client: reqwest::Client,
+        assert_eq!(person.name, "Dave Dumpling");
+        assert_eq!(person.age, 99);
+        Ok(())
     }
 
-    impl TestClient {
-        pub fn new(router: axum::Router) -> anyhow::Result<Self> {
-            let listener = std::net::TcpListener::bind(std::net::SocketAddr::new(LOCALHOST, 0))?;
-            let address = listener.local_addr()?;
+    #[tokio::test]
+    async fn responds_with_not_modified_when_the_etag_matches() -> anyhow::Result<()> {
+        let cached = CachedJsonResponse::new(Bytes::from(r#"{"name":"Erin Eggplant","age":3}"#));
+        let etag = cached.etag().clone();
+        let app = Router::new().route(
+            "/",
+            routing::get(move |headers: HeaderMap| async move { cached.respond_to(&headers) }),
+        );
 
-            // we ignore the handle and let the test runner clean up the server
-            tokio::spawn(async move {
-                axum::Server::from_tcp(listener)
-                    .expect("server error")
-                    .serve(router.into_make_service())
-                    .await
-                    .expect("server error");
-            });
+        let client = TestClient::new(app)?;
+        let response = client.get("/").send().await?;
 
-            let client = reqwest::Client::builder()
-                .redirect(reqwest::redirect::Policy::none())
-                .build()?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("ETag"), Some(&etag));
+        assert_eq!(
+            response.text().await?,
+            r#"{"name":"Erin Eggplant","age":3}"#
+        );
 
-            Ok(TestClient { address, client })
-        }
+        let response = client
+            .get("/")
+            .header("If-None-Match", format!(r#""other", {}"#, etag.to_str()?))
+            .send()
+            .await?;
 
-        pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
-            self.client.get(format!("http://{}{}", self.address, url))
-        }
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get("ETag"), Some(&etag));
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct Person {
+        name: String,
+        age: u16,
     }
 }
@@ -1,4 +1,12 @@
+pub mod capabilities;
 pub mod connector;
 pub mod json_response;
+pub mod metrics;
+pub mod query_cache;
+pub mod query_coalescing;
+pub mod request_context;
 pub mod schema;
 pub mod state;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_client;
+pub mod validation;
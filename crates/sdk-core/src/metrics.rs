@@ -0,0 +1,42 @@
+//! The metrics maintained by the SDK, as opposed to those of the connector.
+//!
+//! The constructors here are for metrics with constant names, help text and labels, and so panic
+//! if they are invalid rather than returning an error.
+
+use prometheus::core::Collector;
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use prometheus::{Opts, Result};
+
+/// A component which maintains metrics, to be registered with the server's metrics registry.
+pub trait Metrics {
+    /// The metrics maintained by the component.
+    fn collectors(&self) -> Vec<Box<dyn Collector>>;
+}
+
+pub fn counter(name: &str, help: &str) -> IntCounter {
+    valid(IntCounter::new(name, help))
+}
+
+pub fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    valid(IntCounterVec::new(Opts::new(name, help), labels))
+}
+
+pub fn gauge(name: &str, help: &str) -> IntGauge {
+    valid(IntGauge::new(name, help))
+}
+
+pub fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    valid(IntGaugeVec::new(Opts::new(name, help), labels))
+}
+
+pub fn histogram_vec(name: &str, help: &str, buckets: Vec<f64>, labels: &[&str]) -> HistogramVec {
+    valid(HistogramVec::new(
+        HistogramOpts::new(name, help).buckets(buckets),
+        labels,
+    ))
+}
+
+/// Unwrap a metric, which can only fail to be constructed if its constant definition is invalid.
+fn valid<T>(metric: Result<T>) -> T {
+    metric.expect("valid metric")
+}
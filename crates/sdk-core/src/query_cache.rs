@@ -0,0 +1,385 @@
+//! A cache of serialized query responses.
+//!
+//! Responses are cached for the collections a connector opts in with
+//! [`Connector::query_cache_ttl`], keyed by the request and the configuration generation, so that
+//! reloading the configuration never serves results computed with the previous one. Cached
+//! responses are stored as serialized JSON, so serving them does not serialize them again.
+//!
+//! The cache is bounded by the total size of its keys and values. When it is full, the oldest
+//! entries are evicted first.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use ndc_models as models;
+use prometheus::core::Collector;
+use prometheus::{IntCounter, IntGauge};
+
+use crate::connector::Connector;
+use crate::metrics::{self, Metrics};
+
+/// A cache of serialized query responses, bounded by the total size of its entries.
+pub struct QueryCache {
+    max_size: usize,
+    entries: Mutex<Entries>,
+    metrics: QueryCacheMetrics,
+}
+
+/// Identifies a cached response: a query request, in the configuration generation in which it was
+/// made.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    generation: u64,
+    request: Bytes,
+}
+
+impl CacheKey {
+    /// The key for a request made in the given configuration generation.
+    ///
+    /// The request is normalized by serializing it: arguments, variables and relationships are
+    /// held in sorted maps, so equivalent requests produce the same key however their JSON was
+    /// laid out. Fields keep their order, as it determines the order of the response.
+    pub fn new(generation: u64, request: &models::QueryRequest) -> serde_json::Result<Self> {
+        Ok(Self {
+            generation,
+            request: serde_json::to_vec(request)?.into(),
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.request.len()
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    values: HashMap<CacheKey, Entry>,
+    // keys in the order they were inserted, with the ID of the entry, which is used to skip keys
+    // which have since been removed or replaced
+    insertion_order: VecDeque<(CacheKey, u64)>,
+    size: usize,
+    next_id: u64,
+}
+
+struct Entry {
+    id: u64,
+    value: Bytes,
+    expires_at: Instant,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.values.remove(key)?;
+        self.size -= key.size() + entry.value.len();
+        Some(entry)
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((key, id)) = self.insertion_order.pop_front() {
+            if self.values.get(&key).is_some_and(|entry| entry.id == id) {
+                self.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl QueryCache {
+    /// A cache holding at most `max_size` bytes of requests and responses.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            entries: Mutex::new(Entries::default()),
+            metrics: QueryCacheMetrics::new(),
+        }
+    }
+
+    /// The cached response to a request, if it has not expired.
+    pub fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        let mut entries = self.entries.lock().expect("query cache lock poisoned");
+        let value = match entries.values.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        self.metrics.size.set(gauge_value(entries.size));
+        drop(entries);
+        match &value {
+            Some(_) => self.metrics.hits.inc(),
+            None => self.metrics.misses.inc(),
+        }
+        value
+    }
+
+    /// Cache the response to a request for `ttl`, evicting the oldest entries to make room.
+    ///
+    /// Responses which are larger than the whole cache are not cached.
+    pub fn insert(&self, key: CacheKey, value: Bytes, ttl: Duration) {
+        let size = key.size() + value.len();
+        if size > self.max_size {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("query cache lock poisoned");
+        entries.remove(&key);
+        while entries.size + size > self.max_size && entries.evict_oldest() {
+            self.metrics.evictions.inc();
+        }
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.size += size;
+        entries.insertion_order.push_back((key.clone(), id));
+        entries.values.insert(
+            key,
+            Entry {
+                id,
+                value,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        // drop keys which were removed or replaced, so that a cache which never fills up does
+        // not accumulate them
+        if entries.insertion_order.len() > 2 * entries.values.len() {
+            let Entries {
+                values,
+                insertion_order,
+                ..
+            } = &mut *entries;
+            insertion_order
+                .retain(|(key, id)| values.get(key).is_some_and(|entry| entry.id == *id));
+        }
+        self.metrics.size.set(gauge_value(entries.size));
+    }
+}
+
+impl Metrics for QueryCache {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.metrics.hits.clone()),
+            Box::new(self.metrics.misses.clone()),
+            Box::new(self.metrics.evictions.clone()),
+            Box::new(self.metrics.size.clone()),
+        ]
+    }
+}
+
+fn gauge_value(size: usize) -> i64 {
+    i64::try_from(size).unwrap_or(i64::MAX)
+}
+
+struct QueryCacheMetrics {
+    hits: IntCounter,
+    misses: IntCounter,
+    evictions: IntCounter,
+    size: IntGauge,
+}
+
+impl QueryCacheMetrics {
+    fn new() -> Self {
+        Self {
+            hits: metrics::counter(
+                "ndc_sdk_query_cache_hits_total",
+                "Number of queries answered from the query cache",
+            ),
+            misses: metrics::counter(
+                "ndc_sdk_query_cache_misses_total",
+                "Number of cacheable queries not found in the query cache",
+            ),
+            evictions: metrics::counter(
+                "ndc_sdk_query_cache_evictions_total",
+                "Number of responses evicted from the query cache to make room for others",
+            ),
+            size: metrics::gauge(
+                "ndc_sdk_query_cache_size_bytes",
+                "Total size of the requests and responses in the query cache",
+            ),
+        }
+    }
+}
+
+/// How long the response to a request may be cached: the shortest TTL of the collections it
+/// involves, or `None` if any of them is not cacheable.
+///
+/// The collections involved are the requested collection, the targets of its relationships, and
+/// any collections used by unrelated `exists` predicates.
+pub fn query_cache_ttl<C: Connector>(
+    configuration: &C::Configuration,
+    request: &models::QueryRequest,
+) -> Option<Duration> {
+    let mut collections = BTreeSet::from([&request.collection]);
+    collections.extend(
+        request
+            .collection_relationships
+            .values()
+            .map(|relationship| &relationship.target_collection),
+    );
+    unrelated_collections(&request.query, &mut collections);
+    collections
+        .into_iter()
+        .map(|collection| C::query_cache_ttl(configuration, collection))
+        .try_fold(Duration::MAX, |ttl, collection_ttl| {
+            Some(ttl.min(collection_ttl?))
+        })
+}
+
+fn unrelated_collections<'a>(
+    query: &'a models::Query,
+    collections: &mut BTreeSet<&'a models::CollectionName>,
+) {
+    for field in query.fields.iter().flat_map(|fields| fields.values()) {
+        field_collections(field, collections);
+    }
+    for element in query
+        .order_by
+        .iter()
+        .flat_map(|order_by| &order_by.elements)
+    {
+        let path = match &element.target {
+            models::OrderByTarget::Column { path, .. }
+            | models::OrderByTarget::Aggregate { path, .. } => path,
+        };
+        path_collections(path, collections);
+    }
+    if let Some(predicate) = &query.predicate {
+        expression_collections(predicate, collections);
+    }
+}
+
+fn field_collections<'a>(
+    field: &'a models::Field,
+    collections: &mut BTreeSet<&'a models::CollectionName>,
+) {
+    match field {
+        models::Field::Column { fields, .. } => {
+            let mut nested_field = fields.as_ref();
+            while let Some(field) = nested_field {
+                nested_field = match field {
+                    models::NestedField::Object(object) => {
+                        for field in object.fields.values() {
+                            field_collections(field, collections);
+                        }
+                        None
+                    }
+                    models::NestedField::Array(array) => Some(&array.fields),
+                    models::NestedField::Collection(collection) => {
+                        unrelated_collections(&collection.query, collections);
+                        None
+                    }
+                };
+            }
+        }
+        models::Field::Relationship { query, .. } => unrelated_collections(query, collections),
+    }
+}
+
+fn path_collections<'a>(
+    path: &'a [models::PathElement],
+    collections: &mut BTreeSet<&'a models::CollectionName>,
+) {
+    for predicate in path
+        .iter()
+        .filter_map(|element| element.predicate.as_deref())
+    {
+        expression_collections(predicate, collections);
+    }
+}
+
+fn expression_collections<'a>(
+    expression: &'a models::Expression,
+    collections: &mut BTreeSet<&'a models::CollectionName>,
+) {
+    match expression {
+        models::Expression::And { expressions } | models::Expression::Or { expressions } => {
+            for expression in expressions {
+                expression_collections(expression, collections);
+            }
+        }
+        models::Expression::Not { expression } => expression_collections(expression, collections),
+        models::Expression::UnaryComparisonOperator { column, .. }
+        | models::Expression::ArrayComparison { column, .. } => {
+            comparison_target_collections(column, collections);
+        }
+        models::Expression::BinaryComparisonOperator { column, value, .. } => {
+            comparison_target_collections(column, collections);
+            if let models::ComparisonValue::Column { path, .. } = value {
+                path_collections(path, collections);
+            }
+        }
+        models::Expression::Exists {
+            in_collection,
+            predicate,
+        } => {
+            if let models::ExistsInCollection::Unrelated { collection, .. } = in_collection {
+                collections.insert(collection);
+            }
+            if let Some(predicate) = predicate {
+                expression_collections(predicate, collections);
+            }
+        }
+    }
+}
+
+fn comparison_target_collections<'a>(
+    target: &'a models::ComparisonTarget,
+    collections: &mut BTreeSet<&'a models::CollectionName>,
+) {
+    if let models::ComparisonTarget::Aggregate { path, .. } = target {
+        path_collections(path, collections);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use ndc_models as models;
+    use serde_json::json;
+
+    use super::{CacheKey, QueryCache};
+
+    fn key(collection: &str) -> anyhow::Result<CacheKey> {
+        let request: models::QueryRequest = serde_json::from_value(json!({
+            "collection": collection,
+            "arguments": {},
+            "query": {},
+            "collection_relationships": {}
+        }))?;
+        Ok(CacheKey::new(0, &request)?)
+    }
+
+    #[test]
+    fn serves_cached_responses_until_they_expire() -> anyhow::Result<()> {
+        let cache = QueryCache::new(1024);
+        cache.insert(key("articles")?, Bytes::from("[]"), Duration::from_secs(60));
+        cache.insert(key("authors")?, Bytes::from("[]"), Duration::ZERO);
+
+        assert_eq!(cache.get(&key("articles")?), Some(Bytes::from("[]")));
+        assert_eq!(cache.get(&key("authors")?), None);
+        assert_eq!(cache.metrics.hits.get(), 1);
+        assert_eq!(cache.metrics.misses.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_the_oldest_responses_when_full() -> anyhow::Result<()> {
+        let response = Bytes::from(vec![b' '; 100]);
+        let entry_size = key("articles")?.size() + response.len();
+        let cache = QueryCache::new(2 * entry_size);
+
+        for collection in ["articles", "authors", "comments"] {
+            cache.insert(key(collection)?, response.clone(), Duration::from_secs(60));
+        }
+
+        assert_eq!(cache.get(&key("articles")?), None);
+        assert!(cache.get(&key("authors")?).is_some());
+        assert!(cache.get(&key("comments")?).is_some());
+        assert_eq!(cache.metrics.evictions.get(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+//! Coalescing of identical concurrent queries.
+//!
+//! When a query arrives while an identical one is already running, it waits for that query's
+//! result rather than calling the connector again. Queries are identical if they have the same
+//! [`CacheKey`], i.e. the same request in the same configuration generation. Request headers are
+//! not compared, so this is only suitable for connectors whose results do not depend on them.
+//!
+//! The result is shared as serialized JSON. Streamed responses are not shared, as they are not
+//! held in memory; queries waiting on one run on their own instead. If the query being waited on
+//! is abandoned, because its request was cancelled or exceeded its deadline, one of the waiting
+//! queries runs in its place, and the others wait for that instead.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use http::StatusCode;
+use ndc_models as models;
+use prometheus::core::Collector;
+use prometheus::IntCounter;
+use tokio::sync::watch;
+
+use crate::connector::{ErrorResponse, Result};
+use crate::json_response::JsonResponse;
+use crate::metrics::{self, Metrics};
+use crate::query_cache::CacheKey;
+use crate::request_context::RequestContext;
+
+/// The outcome of a query, as seen by the identical queries waiting on it.
+#[derive(Clone)]
+enum Shared {
+    Result(Result<Bytes>),
+    Streamed,
+}
+
+/// Runs identical concurrent queries once, sharing the result between them.
+pub struct QueryCoalescer {
+    in_flight: Mutex<InFlight>,
+    coalesced: IntCounter,
+}
+
+#[derive(Default)]
+struct InFlight {
+    queries: HashMap<CacheKey, (u64, watch::Receiver<Option<Shared>>)>,
+    next_id: u64,
+}
+
+impl Default for QueryCoalescer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QueryCoalescer {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(InFlight::default()),
+            coalesced: metrics::counter(
+                "ndc_sdk_query_coalesced_total",
+                "Number of queries answered with the result of an identical query which was already running",
+            ),
+        }
+    }
+
+    /// Run the query, unless an identical one is already running, in which case wait for its
+    /// result instead.
+    ///
+    /// Waiting stops if the request is cancelled. Its deadline is not enforced here, so the caller
+    /// should bound this by the deadline, in order that it applies to the wait as well as to the
+    /// query.
+    pub async fn run(
+        &self,
+        key: CacheKey,
+        request_context: &RequestContext,
+        query: impl Future<Output = Result<JsonResponse<models::QueryResponse>>>,
+    ) -> Result<JsonResponse<models::QueryResponse>> {
+        loop {
+            let (sender, _guard) = match self.join(key.clone()) {
+                Role::Leader(sender, guard) => (sender, guard),
+                Role::Follower(mut receiver) => {
+                    let shared = tokio::select! {
+                        result = receiver.wait_for(Option::is_some) => {
+                            result.ok().and_then(|shared| shared.clone())
+                        }
+                        () = request_context.cancellation_token().cancelled() => {
+                            return Err(ErrorResponse::new(
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "Request cancelled".to_owned(),
+                                serde_json::Value::Null,
+                            ));
+                        }
+                    };
+                    match shared {
+                        Some(Shared::Result(result)) => {
+                            self.coalesced.inc();
+                            return result.map(JsonResponse::Serialized);
+                        }
+                        Some(Shared::Streamed) => return query.await,
+                        // the query we were waiting on was abandoned, so run it in its place
+                        None => continue,
+                    }
+                }
+            };
+
+            let result = match query.await {
+                Ok(JsonResponse::Streamed(stream)) => {
+                    sender.send_replace(Some(Shared::Streamed));
+                    return Ok(JsonResponse::Streamed(stream));
+                }
+                Ok(response) => response.into_bytes::<ErrorResponse>().await,
+                Err(err) => Err(err),
+            };
+            // a failure caused by this request being cancelled says nothing about the others
+            if result.is_ok() || !request_context.cancellation_token().is_cancelled() {
+                sender.send_replace(Some(Shared::Result(result.clone())));
+            }
+            return result.map(JsonResponse::Serialized);
+        }
+    }
+
+    /// Wait for the identical query if there is one, or else become the query others wait for.
+    fn join(&self, key: CacheKey) -> Role<'_> {
+        let mut in_flight = self
+            .in_flight
+            .lock()
+            .expect("in-flight queries lock poisoned");
+        if let Some((_, receiver)) = in_flight.queries.get(&key) {
+            return Role::Follower(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        let id = in_flight.next_id;
+        in_flight.next_id += 1;
+        in_flight.queries.insert(key.clone(), (id, receiver));
+        Role::Leader(
+            sender,
+            InFlightGuard {
+                coalescer: self,
+                key,
+                id,
+            },
+        )
+    }
+}
+
+impl Metrics for QueryCoalescer {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![Box::new(self.coalesced.clone())]
+    }
+}
+
+enum Role<'a> {
+    Leader(watch::Sender<Option<Shared>>, InFlightGuard<'a>),
+    Follower(watch::Receiver<Option<Shared>>),
+}
+
+/// Stops sharing a query once it completes or is cancelled.
+struct InFlightGuard<'a> {
+    coalescer: &'a QueryCoalescer,
+    key: CacheKey,
+    id: u64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .coalescer
+            .in_flight
+            .lock()
+            .expect("in-flight queries lock poisoned");
+        if in_flight
+            .queries
+            .get(&self.key)
+            .is_some_and(|(id, _)| *id == self.id)
+        {
+            in_flight.queries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_models as models;
+    use serde_json::json;
+    use tokio::sync::oneshot;
+
+    use crate::connector::ErrorResponse;
+    use crate::json_response::JsonResponse;
+    use crate::query_cache::CacheKey;
+    use crate::request_context::RequestContext;
+
+    use super::QueryCoalescer;
+
+    fn key() -> anyhow::Result<CacheKey> {
+        let request: models::QueryRequest = serde_json::from_value(json!({
+            "collection": "articles",
+            "arguments": {},
+            "query": {},
+            "collection_relationships": {}
+        }))?;
+        Ok(CacheKey::new(0, &request)?)
+    }
+
+    #[tokio::test]
+    async fn shares_the_result_of_an_identical_running_query() -> anyhow::Result<()> {
+        let coalescer = QueryCoalescer::new();
+        let (finish, finished) = oneshot::channel::<()>();
+
+        let request_context = RequestContext::default();
+
+        let leader = coalescer.run(key()?, &request_context, async {
+            finished.await.ok();
+            Ok(JsonResponse::Value(models::QueryResponse(vec![])))
+        });
+        let follower = coalescer.run(key()?, &request_context, async {
+            Err(ErrorResponse::from(
+                "the identical query should not run again".to_owned(),
+            ))
+        });
+        let (leader, follower, ()) = tokio::join!(leader, follower, async {
+            tokio::task::yield_now().await;
+            finish.send(()).ok();
+        });
+
+        let leader = leader?.into_bytes::<ErrorResponse>().await?;
+        let follower = follower?.into_bytes::<ErrorResponse>().await?;
+        assert_eq!(leader, follower);
+        assert_eq!(coalescer.coalesced.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_queries_again_once_they_complete() -> anyhow::Result<()> {
+        let coalescer = QueryCoalescer::new();
+
+        for _ in 0..2 {
+            coalescer
+                .run(key()?, &RequestContext::default(), async {
+                    Ok(JsonResponse::Value(models::QueryResponse(vec![])))
+                })
+                .await?;
+        }
+
+        assert_eq!(coalescer.coalesced.get(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_the_query_again_if_the_leader_is_cancelled() -> anyhow::Result<()> {
+        let coalescer = QueryCoalescer::new();
+        let leader_context = RequestContext::default();
+        let follower_context = RequestContext::default();
+        let (cancelled, cancel) = oneshot::channel::<()>();
+
+        let leader = coalescer.run(key()?, &leader_context, async {
+            cancel.await.ok();
+            leader_context.cancellation_token().cancel();
+            Err(ErrorResponse::from("cancelled".to_owned()))
+        });
+        let follower = coalescer.run(key()?, &follower_context, async {
+            Ok(JsonResponse::Value(models::QueryResponse(vec![])))
+        });
+        let (leader, follower, ()) = tokio::join!(leader, follower, async {
+            tokio::task::yield_now().await;
+            cancelled.send(()).ok();
+        });
+
+        assert!(leader.is_err());
+        follower?;
+        assert_eq!(coalescer.coalesced.get(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_the_query_again_if_the_leader_is_dropped() -> anyhow::Result<()> {
+        let coalescer = QueryCoalescer::new();
+        let request_context = RequestContext::default();
+
+        // the leader never completes, and is dropped when its deadline passes
+        let leader = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            coalescer.run(key()?, &request_context, std::future::pending()),
+        );
+        let follower = coalescer.run(key()?, &request_context, async {
+            Ok(JsonResponse::Value(models::QueryResponse(vec![])))
+        });
+        let (leader, follower) = tokio::join!(leader, follower);
+
+        assert!(leader.is_err());
+        follower?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_when_the_follower_is_cancelled() -> anyhow::Result<()> {
+        let coalescer = QueryCoalescer::new();
+        let leader_context = RequestContext::default();
+        let follower_context = RequestContext::default();
+
+        let leader = coalescer.run(key()?, &leader_context, std::future::pending());
+        let follower = coalescer.run(key()?, &follower_context, async {
+            Err(ErrorResponse::from(
+                "the identical query should not run again".to_owned(),
+            ))
+        });
+        // polled in order, so that the leader starts first
+        let result = tokio::select! {
+            biased;
+            _ = leader => panic!("the leader should not complete"),
+            result = follower => result,
+            () = async {
+                tokio::task::yield_now().await;
+                follower_context.cancellation_token().cancel();
+                std::future::pending::<()>().await;
+            } => unreachable!(),
+        };
+
+        let Err(err) = result else {
+            panic!("the follower should fail");
+        };
+        assert!(err.to_string().starts_with("503"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Information about the incoming HTTP request, made available to connectors.
+
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, HeaderName};
+use tokio_util::sync::CancellationToken;
+
+/// The name of the header used to correlate requests across services.
+pub const REQUEST_ID_HEADER_NAME: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Per-request context, passed to each [`Connector`](crate::connector::Connector) method which
+/// handles a request.
+///
+/// This gives connectors access to the incoming headers (for example, to forward them to an
+/// upstream service), a request ID for tagging logs, the version of the NDC specification
+/// requested by the caller, the tracing span of the request, a token which is cancelled if the
+/// request is abandoned, and the request's deadline, if any.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    headers: HeaderMap,
+    request_id: String,
+    ndc_version: Option<semver::Version>,
+    span: tracing::Span,
+    cancellation_token: CancellationToken,
+    deadline: Option<Instant>,
+}
+
+impl RequestContext {
+    /// Construct a request context from the headers of an incoming request.
+    ///
+    /// The request ID is read from the `X-Request-Id` header, or generated if it is absent. The
+    /// NDC version is read from the `X-Hasura-NDC-Version` header, if present and valid.
+    ///
+    /// The current span is assumed to be the span of the request.
+    pub fn new(headers: HeaderMap) -> Self {
+        let request_id = headers
+            .get(REQUEST_ID_HEADER_NAME)
+            .and_then(|value| value.to_str().ok())
+            .map_or_else(generate_request_id, ToOwned::to_owned);
+        let ndc_version = headers
+            .get(ndc_models::VERSION_HEADER_NAME)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| semver::Version::parse(value).ok());
+        Self {
+            headers,
+            request_id,
+            ndc_version,
+            span: tracing::Span::current(),
+            cancellation_token: CancellationToken::new(),
+            deadline: None,
+        }
+    }
+
+    /// Replace the cancellation token of the request.
+    #[must_use]
+    pub fn with_cancellation_token(self, cancellation_token: CancellationToken) -> Self {
+        Self {
+            cancellation_token,
+            ..self
+        }
+    }
+
+    /// The headers sent with the request.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// An identifier for the request, suitable for correlating logs.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// The version of the NDC specification requested by the caller, if any.
+    ///
+    /// If this is present, the SDK has already checked that it is compatible with the version
+    /// supported by the connector.
+    pub fn ndc_version(&self) -> Option<&semver::Version> {
+        self.ndc_version.as_ref()
+    }
+
+    /// The tracing span of the request.
+    ///
+    /// Its parent is the caller's trace context, if one was propagated.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Set the deadline of the request.
+    #[must_use]
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// A token which is cancelled when the request is abandoned, because the client disconnected,
+    /// the deadline passed, or the server is shutting down.
+    ///
+    /// Connectors can use this to abort expensive work, such as a running database query, whose
+    /// result will never be used.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    /// The instant after which the SDK will abandon the request, if any. This bounds streamed
+    /// responses too: one which has not ended by the deadline is aborted.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time remaining until the deadline, if any.
+    ///
+    /// This is suitable for passing on as a timeout to upstream services. It is zero once the
+    /// deadline has passed.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self::new(HeaderMap::new())
+    }
+}
+
+fn generate_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn reads_request_id_and_version_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER_NAME, HeaderValue::from_static("abc123"));
+        headers.insert(
+            ndc_models::VERSION_HEADER_NAME,
+            HeaderValue::from_static("0.2.0"),
+        );
+
+        let context = RequestContext::new(headers);
+
+        assert_eq!(context.request_id(), "abc123");
+        assert_eq!(context.ndc_version(), Some(&semver::Version::new(0, 2, 0)));
+    }
+
+    #[test]
+    fn generates_a_request_id_when_absent() {
+        let context = RequestContext::new(HeaderMap::new());
+
+        assert_eq!(context.request_id().len(), 32);
+        assert_eq!(context.ndc_version(), None);
+    }
+}
@@ -1,5 +1,7 @@
 use std::{io::Write, path::Path};
 
+use futures_util::TryStreamExt;
+
 use crate::{
     connector::{Connector, ConnectorSetup, Result},
     json_response::JsonResponse,
@@ -32,35 +34,41 @@ where
     let schema = Setup::Connector::get_schema(server_state.configuration()).await?;
     let capabilities = get_capabilities::<Setup::Connector>().await;
 
-    print_json_schema_and_capabilities(writer, schema, capabilities)?;
+    print_json_schema_and_capabilities(writer, schema, capabilities).await?;
 
     Ok(())
 }
 
 /// This foulness manually writes out a JSON object with schema and capabilities properties.
 /// We do it like this to avoid having to deserialize and reserialize any
-/// JsonResponse::Serialized values.
-fn print_json_schema_and_capabilities<W: Write>(
+/// JsonResponse::Serialized values, and to write JsonResponse::Streamed values as they arrive.
+async fn print_json_schema_and_capabilities<W: Write>(
     mut writer: W,
     schema: JsonResponse<ndc_models::SchemaResponse>,
     capabilities: JsonResponse<ndc_models::CapabilitiesResponse>,
 ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
     write!(writer, r#"{{"schema":"#)?;
-    write_json_response(&mut writer, schema)?;
+    write_json_response(&mut writer, schema).await?;
     write!(writer, r#","capabilities":"#)?;
-    write_json_response(&mut writer, capabilities)?;
+    write_json_response(&mut writer, capabilities).await?;
     writeln!(writer, r#"}}"#)?;
 
     Ok(())
 }
 
-fn write_json_response<W: Write, A: serde::Serialize>(
+async fn write_json_response<W: Write, A: serde::Serialize>(
     writer: &mut W,
     json: JsonResponse<A>,
 ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
     match json {
         JsonResponse::Value(value) => Ok(serde_json::to_writer(writer, &value)?),
         JsonResponse::Serialized(bytes) => Ok(writer.write_all(&bytes)?),
+        JsonResponse::Streamed(mut stream) => {
+            while let Some(chunk) = stream.try_next().await? {
+                writer.write_all(&chunk)?;
+            }
+            Ok(())
+        }
     }
 }
 
@@ -85,7 +93,9 @@ mod tests {
             let mut bytes = Cursor::new(vec![]);
             let schema = Example::get_schema(&()).await.unwrap();
             let capabilities = get_capabilities::<Example>().await;
-            print_json_schema_and_capabilities(&mut bytes, schema, capabilities).unwrap();
+            print_json_schema_and_capabilities(&mut bytes, schema, capabilities)
+                .await
+                .unwrap();
 
             let bytes = bytes.into_inner();
             serde_json::from_slice::<SchemaAndCapabilities>(&bytes).unwrap();
@@ -1,29 +1,161 @@
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
-use prometheus::Registry;
-use tokio::sync::OnceCell;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntGauge, Registry};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_util::sync::CancellationToken;
 
 use crate::connector::error::*;
 use crate::connector::{Connector, ConnectorSetup};
+use crate::json_response::CachedJsonResponse;
+use crate::metrics::{self, Metrics};
 
 /// Everything we need to keep in memory.
 pub struct ServerState<C: Connector> {
     configuration: C::Configuration,
+    shared: Arc<SharedState<C>>,
+    // a snapshot of the current connector state, taken when this value was created or cloned
     state: Arc<ConnectorState<C>>,
-    metrics: prometheus::Registry,
+    shutdown_token: CancellationToken,
+    generation: u64,
+}
+
+/// The parts of the server state shared by every clone.
+struct SharedState<C: Connector> {
+    current: RwLock<Arc<ConnectorState<C>>>,
+    init_state: Arc<dyn ConnectorSetup<Connector = C>>,
+    state_metrics: StateMetrics,
+    sdk_metrics: SdkMetrics,
+    initializing_in_background: AtomicBool,
+    schema: OnceCell<CachedJsonResponse>,
+    parsed_schema: OnceCell<ndc_models::SchemaResponse>,
+    capabilities: OnceCell<CachedJsonResponse>,
+    parsed_capabilities: OnceCell<ndc_models::CapabilitiesResponse>,
 }
 
-/// The connector state, which may or may not be initialized.
+/// The connector state, which may or may not be initialized, and the metrics registry it
+/// registers its metrics with.
 struct ConnectorState<C: Connector> {
     cell: OnceCell<C::State>,
-    init_state: Box<dyn ConnectorSetup<Connector = C>>,
+    metrics: prometheus::Registry,
+    // when the metrics registry was created, and so when its cumulative metrics started counting
+    created: SystemTime,
+}
+
+impl<C: Connector> ConnectorState<C> {
+    fn new(metrics: prometheus::Registry, sdk_metrics: &SdkMetrics) -> Self {
+        if let Err(err) = sdk_metrics.register(&metrics) {
+            tracing::warn!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Unable to register metrics",
+                name = "Unable to register metrics",
+                body = %err,
+            );
+        }
+        Self {
+            cell: OnceCell::new(),
+            metrics,
+            created: SystemTime::now(),
+        }
+    }
+}
+
+/// Metrics describing the lifecycle of the connector state.
+///
+/// These are shared across each metrics registry, so that they are not reset when the state is
+/// re-initialized.
+#[derive(Clone)]
+struct StateMetrics {
+    initialized: IntGauge,
+    initializations: IntCounter,
+    initialization_failures: IntCounter,
+    invalidations: IntCounter,
+}
+
+impl StateMetrics {
+    fn new() -> Self {
+        Self {
+            initialized: metrics::gauge(
+                "ndc_sdk_state_initialized",
+                "Whether the connector state is currently initialized",
+            ),
+            initializations: metrics::counter(
+                "ndc_sdk_state_initializations_total",
+                "Number of times the connector state was initialized",
+            ),
+            initialization_failures: metrics::counter(
+                "ndc_sdk_state_initialization_failures_total",
+                "Number of times the connector state failed to initialize",
+            ),
+            invalidations: metrics::counter(
+                "ndc_sdk_state_invalidations_total",
+                "Number of times the connector state was invalidated",
+            ),
+        }
+    }
+}
+
+impl Metrics for StateMetrics {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.initialized.clone()),
+            Box::new(self.initializations.clone()),
+            Box::new(self.initialization_failures.clone()),
+            Box::new(self.invalidations.clone()),
+        ]
+    }
+}
+
+/// Metrics maintained by the SDK rather than the connector.
+///
+/// The connector registers its metrics each time the state is initialized, but these are
+/// registered with each new metrics registry by the SDK, and shared by every generation of the
+/// server state.
+#[derive(Clone, Default)]
+struct SdkMetrics(Arc<RwLock<Vec<SharedCollector>>>);
+
+impl SdkMetrics {
+    fn add(&self, collector: SharedCollector) {
+        self.0
+            .write()
+            .expect("metrics lock poisoned")
+            .push(collector);
+    }
+
+    fn register(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        for collector in self.0.read().expect("metrics lock poisoned").iter() {
+            registry.register(Box::new(collector.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A collector which can be registered with several registries at once.
+#[derive(Clone)]
+struct SharedCollector(Arc<dyn Collector>);
+
+impl Collector for SharedCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.0.collect()
+    }
 }
 
 // Server state must be cloneable even if the underlying connector is not.
 // We only require `Connector::Configuration` to be cloneable.
 //
 // Server state is always stored in an `Arc`, so is therefore cloneable.
+//
+// Cloning takes a snapshot of the current connector state, which may differ from the original's
+// if the state has been invalidated since.
 impl<C: Connector> Clone for ServerState<C>
 where
     C::Configuration: Clone,
@@ -31,12 +163,51 @@ where
     fn clone(&self) -> Self {
         Self {
             configuration: self.configuration.clone(),
-            state: self.state.clone(),
-            metrics: self.metrics.clone(),
+            shared: self.shared.clone(),
+            state: self.shared.current(),
+            shutdown_token: self.shutdown_token.clone(),
+            generation: self.generation,
         }
     }
 }
 
+impl<C: Connector> SharedState<C> {
+    fn new(init_state: Arc<dyn ConnectorSetup<Connector = C>>, metrics: Registry) -> Self {
+        let state_metrics = StateMetrics::new();
+        let sdk_metrics = SdkMetrics::default();
+        for collector in state_metrics.collectors() {
+            sdk_metrics.add(SharedCollector(Arc::from(collector)));
+        }
+        Self::new_with_metrics(init_state, metrics, state_metrics, sdk_metrics)
+    }
+
+    fn new_with_metrics(
+        init_state: Arc<dyn ConnectorSetup<Connector = C>>,
+        metrics: Registry,
+        state_metrics: StateMetrics,
+        sdk_metrics: SdkMetrics,
+    ) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(ConnectorState::new(metrics, &sdk_metrics))),
+            init_state,
+            state_metrics,
+            sdk_metrics,
+            initializing_in_background: AtomicBool::new(false),
+            schema: OnceCell::new(),
+            parsed_schema: OnceCell::new(),
+            capabilities: OnceCell::new(),
+            parsed_capabilities: OnceCell::new(),
+        }
+    }
+
+    fn current(&self) -> Arc<ConnectorState<C>> {
+        self.current
+            .read()
+            .expect("connector state lock poisoned")
+            .clone()
+    }
+}
+
 impl<C: Connector> ServerState<C> {
     /// Construct a new server state.
     pub fn new(
@@ -44,13 +215,13 @@ impl<C: Connector> ServerState<C> {
         init_state: impl ConnectorSetup<Connector = C> + 'static,
         metrics: prometheus::Registry,
     ) -> Self {
+        let shared = Arc::new(SharedState::new(Arc::new(init_state), metrics));
         Self {
             configuration,
-            state: Arc::new(ConnectorState {
-                cell: OnceCell::new(),
-                init_state: Box::new(init_state),
-            }),
-            metrics,
+            state: shared.current(),
+            shared,
+            shutdown_token: CancellationToken::new(),
+            generation: 0,
         }
     }
 
@@ -68,17 +239,345 @@ impl<C: Connector> ServerState<C> {
         self.state
             .cell
             .get_or_try_init(|| async {
-                self.state
+                let result = self
+                    .shared
                     .init_state
-                    .try_init_state(&self.configuration, &mut self.metrics.clone())
-                    .await
+                    .try_init_state(&self.configuration, &mut self.state.metrics.clone())
+                    .await;
+                match result {
+                    Ok(_) => {
+                        self.shared.state_metrics.initializations.inc();
+                        self.shared.state_metrics.initialized.set(1);
+                    }
+                    Err(_) => self.shared.state_metrics.initialization_failures.inc(),
+                }
+                result
             })
             .await
     }
 
+    /// Drop the transient server state, so that it is initialized again when it is next used.
+    ///
+    /// This is intended for when the state is known to be unusable, for example if a connection
+    /// pool is permanently broken. Connectors can trigger this by returning an error marked with
+    /// [`ErrorResponse::with_invalidate_state`].
+    ///
+    /// Requests which are in flight continue to use the previous state. As the new state will
+    /// register its metrics again, it is given a new metrics registry.
+    pub fn invalidate_state(&self) {
+        let mut current = self
+            .shared
+            .current
+            .write()
+            .expect("connector state lock poisoned");
+        // only invalidate the state this snapshot was using, as it may already have been replaced
+        if Arc::ptr_eq(&current, &self.state) && self.state.cell.initialized() {
+            *current = Arc::new(ConnectorState::new(
+                Registry::new(),
+                &self.shared.sdk_metrics,
+            ));
+            self.shared.state_metrics.invalidations.inc();
+            self.shared.state_metrics.initialized.set(0);
+        }
+    }
+
+    /// Whether the state is being initialized in the background, and has not yet succeeded.
+    ///
+    /// See [`ServerState::init_state_in_background`].
+    pub fn is_initializing_in_background(&self) -> bool {
+        self.shared
+            .initializing_in_background
+            .load(Ordering::Acquire)
+    }
+
     /// The server metrics.
+    ///
+    /// This is the registry provided to [`ConnectorSetup::try_init_state`]. It is replaced if the
+    /// state is invalidated.
     pub fn metrics(&self) -> &prometheus::Registry {
-        &self.metrics
+        &self.state.metrics
+    }
+
+    /// When the metrics registry was created, which is the start of the period over which its
+    /// cumulative metrics, such as counters, have been counting.
+    pub fn metrics_start_time(&self) -> SystemTime {
+        self.state.created
+    }
+
+    /// Register metrics maintained outside of the connector, such as by the HTTP server.
+    ///
+    /// Unlike metrics registered by the connector in [`ConnectorSetup::try_init_state`], these
+    /// are registered again with each new metrics registry, so they carry on counting when the
+    /// state is invalidated or the configuration is reloaded.
+    pub fn register_metrics(&self, collector: Box<dyn Collector>) -> prometheus::Result<()> {
+        let collector = SharedCollector(Arc::from(collector));
+        self.state.metrics.register(Box::new(collector.clone()))?;
+        self.shared.sdk_metrics.add(collector);
+        Ok(())
+    }
+
+    /// A token which is cancelled when the server begins shutting down.
+    ///
+    /// The cancellation token of each request is a child of this token.
+    pub fn shutdown_token(&self) -> &CancellationToken {
+        &self.shutdown_token
+    }
+
+    /// The generation of the server state, which starts at zero and is incremented each time the
+    /// configuration is reloaded.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The serialized schema of the connector.
+    ///
+    /// This is computed by [`Connector::get_schema`] on first use, and reused until the
+    /// configuration is reloaded.
+    pub async fn schema(&self) -> Result<&CachedJsonResponse> {
+        self.shared
+            .schema
+            .get_or_try_init(|| async {
+                let schema = C::get_schema(&self.configuration).await?;
+                Ok(CachedJsonResponse::new(
+                    schema.into_bytes::<ErrorResponse>().await?,
+                ))
+            })
+            .await
+    }
+
+    /// The schema of the connector, for checking requests and responses against.
+    ///
+    /// This is parsed from [`ServerState::schema`] on first use, and reused until the
+    /// configuration is reloaded.
+    pub async fn parsed_schema(&self) -> Result<&ndc_models::SchemaResponse> {
+        self.shared
+            .parsed_schema
+            .get_or_try_init(|| async {
+                serde_json::from_slice(self.schema().await?.bytes())
+                    .map_err(ErrorResponse::from_error)
+            })
+            .await
+    }
+
+    /// The serialized capabilities of the connector.
+    ///
+    /// This is computed by [`Connector::get_capabilities`] on first use, and reused until the
+    /// configuration is reloaded.
+    pub async fn capabilities(&self) -> Result<&CachedJsonResponse> {
+        self.shared
+            .capabilities
+            .get_or_try_init(|| async {
+                let capabilities = crate::schema::get_capabilities::<C>().await;
+                Ok(CachedJsonResponse::new(
+                    capabilities.into_bytes::<ErrorResponse>().await?,
+                ))
+            })
+            .await
+    }
+
+    /// The capabilities of the connector, for checking requests against.
+    ///
+    /// This is parsed from [`ServerState::capabilities`] on first use, and reused until the
+    /// configuration is reloaded.
+    pub async fn parsed_capabilities(&self) -> Result<&ndc_models::CapabilitiesResponse> {
+        self.shared
+            .parsed_capabilities
+            .get_or_try_init(|| async {
+                serde_json::from_slice(self.capabilities().await?.bytes())
+                    .map_err(ErrorResponse::from_error)
+            })
+            .await
+    }
+
+    /// Construct the next generation of the server state by parsing the configuration again and
+    /// initializing a new connector state from it.
+    ///
+    /// The new generation has its own metrics registry, as connectors register their metrics when
+    /// the state is initialized.
+    ///
+    /// This fails if the configuration cannot be parsed or the state cannot be initialized, in
+    /// which case the current generation is unaffected.
+    pub async fn reload(&self, config_directory: &Path) -> Result<Self> {
+        let init_state = self.shared.init_state.clone();
+        let configuration = init_state.parse_configuration(config_directory).await?;
+        let shared = Arc::new(SharedState::new_with_metrics(
+            init_state,
+            Registry::new(),
+            self.shared.state_metrics.clone(),
+            self.shared.sdk_metrics.clone(),
+        ));
+        let next = Self {
+            configuration,
+            state: shared.current(),
+            shared,
+            shutdown_token: self.shutdown_token.clone(),
+            generation: self.generation + 1,
+        };
+        next.state().await?;
+        Ok(next)
+    }
+}
+
+impl<C: Connector> ServerState<C>
+where
+    C::Configuration: Clone,
+{
+    /// Start initializing the state in the background, rather than waiting for the first request
+    /// which uses it.
+    ///
+    /// Initialization is retried with exponential backoff until it succeeds or the server shuts
+    /// down. Until then, [`ServerState::is_initializing_in_background`] returns `true`.
+    pub fn init_state_in_background(&self, backoff: Backoff) -> tokio::task::JoinHandle<()> {
+        self.shared
+            .initializing_in_background
+            .store(true, Ordering::Release);
+        let state = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                () = state.init_state_with_retry(backoff) => (),
+                () = state.shutdown_token.cancelled() => (),
+            }
+            state
+                .shared
+                .initializing_in_background
+                .store(false, Ordering::Release);
+        })
+    }
+
+    async fn init_state_with_retry(&self, backoff: Backoff) {
+        let mut attempt = 0;
+        loop {
+            match self.state().await {
+                Ok(_) => {
+                    tracing::info!(
+                        meta.signal_type = "log",
+                        event.domain = "ndc",
+                        event.name = "Connector state initialized",
+                        name = "Connector state initialized",
+                        body =
+                            format!("Connector state initialized after {} attempts", attempt + 1),
+                        attempts = attempt + 1,
+                    );
+                    return;
+                }
+                Err(err) => {
+                    let delay = backoff.delay(attempt);
+                    tracing::warn!(
+                        meta.signal_type = "log",
+                        event.domain = "ndc",
+                        event.name = "Connector state initialization failed",
+                        name = "Connector state initialization failed",
+                        body = format!("{err}; retrying in {delay:?}"),
+                        attempts = attempt + 1,
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Exponential backoff with jitter, for retrying state initialization.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+    /// The maximum delay between retries.
+    pub max_delay: Duration,
+    /// The factor by which the delay increases after each retry.
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the given retry, counting from zero.
+    ///
+    /// This is chosen at random between half and all of the exponentially-increasing delay, so
+    /// that many servers restarting together do not retry in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(delay * (0.5 + rand::random::<f64>() / 2.0))
+    }
+}
+
+/// Server state which can be replaced while the server is running, for example when the
+/// configuration changes.
+///
+/// Each request works with a snapshot of the current [`ServerState`], so requests which are in
+/// flight when the state is replaced finish using the previous generation.
+pub struct ReloadableServerState<C: Connector> {
+    current: Arc<RwLock<ServerState<C>>>,
+    // held while reloading, so that concurrent reloads do not race each other
+    reloading: Arc<Mutex<()>>,
+}
+
+impl<C: Connector> Clone for ReloadableServerState<C> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+            reloading: self.reloading.clone(),
+        }
+    }
+}
+
+impl<C: Connector> From<ServerState<C>> for ReloadableServerState<C> {
+    fn from(state: ServerState<C>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(state)),
+            reloading: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+impl<C: Connector> ReloadableServerState<C>
+where
+    C::Configuration: Clone,
+{
+    /// A snapshot of the current server state.
+    pub fn current(&self) -> ServerState<C> {
+        self.current
+            .read()
+            .expect("server state lock poisoned")
+            .clone()
+    }
+
+    /// Replace the current server state. Subsequent requests will use the new state.
+    pub fn replace(&self, state: ServerState<C>) {
+        *self.current.write().expect("server state lock poisoned") = state;
+    }
+
+    /// Parse the configuration again and, if successful, replace the current server state with
+    /// the next generation.
+    ///
+    /// See [`ServerState::reload`].
+    pub async fn reload(&self, config_directory: &Path) -> Result<u64> {
+        let _reloading = self.reloading.lock().await;
+        let next = self.current().reload(config_directory).await?;
+        let generation = next.generation();
+        self.replace(next);
+        Ok(generation)
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<C: Connector> axum::extract::FromRef<ReloadableServerState<C>> for ServerState<C>
+where
+    C::Configuration: Clone,
+{
+    fn from_ref(input: &ReloadableServerState<C>) -> Self {
+        input.current()
     }
 }
 
@@ -91,3 +590,125 @@ pub async fn init_server_state<Setup: ConnectorSetup>(
     let configuration = setup.parse_configuration(config_directory).await?;
     Ok(ServerState::new(configuration, setup, metrics))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::connector::example::Example;
+
+    use super::{init_server_state, Backoff, ReloadableServerState, ServerState};
+
+    #[tokio::test]
+    async fn reloading_replaces_the_current_generation() -> anyhow::Result<()> {
+        let state =
+            ReloadableServerState::from(init_server_state(Example {}, Path::new(".")).await?);
+        let previous = state.current();
+
+        let generation = state.reload(Path::new(".")).await?;
+
+        assert_eq!(generation, 1);
+        assert_eq!(state.current().generation(), 1);
+        assert_eq!(previous.generation(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalidating_the_state_initializes_it_again() -> anyhow::Result<()> {
+        let state = init_server_state(Example {}, Path::new(".")).await?;
+        state.state().await?;
+
+        state.invalidate_state();
+        let next = state.clone();
+        next.state().await?;
+
+        assert_eq!(counter(&next, "ndc_sdk_state_initializations_total"), 2);
+        assert_eq!(counter(&next, "ndc_sdk_state_invalidations_total"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_increases_exponentially_up_to_the_maximum() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+        };
+
+        for (attempt, expected) in [(0, 1), (1, 2), (2, 4), (3, 8), (4, 10), (100, 10)] {
+            let delay = backoff.delay(attempt);
+            let expected = Duration::from_secs(expected);
+            assert!(delay >= expected / 2 && delay <= expected, "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_the_schema_until_the_configuration_is_reloaded() -> anyhow::Result<()> {
+        let state = init_server_state(Example::default(), Path::new(".")).await?;
+
+        let schema = state.schema().await?;
+        assert!(std::ptr::eq(schema, state.clone().schema().await?));
+
+        let next = state.reload(Path::new(".")).await?;
+        let next_schema = next.schema().await?;
+        assert!(!std::ptr::eq(schema, next_schema));
+        assert_eq!(schema.etag(), next_schema.etag());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caches_the_parsed_schema_until_the_configuration_is_reloaded() -> anyhow::Result<()> {
+        let state = init_server_state(Example::default(), Path::new(".")).await?;
+
+        let schema = state.parsed_schema().await?;
+        assert!(std::ptr::eq(schema, state.clone().parsed_schema().await?));
+
+        let next = state.reload(Path::new(".")).await?;
+        let next_schema = next.parsed_schema().await?;
+        assert!(!std::ptr::eq(schema, next_schema));
+        assert_eq!(schema, next_schema);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_registered_metrics_when_the_configuration_is_reloaded() -> anyhow::Result<()> {
+        let state = init_server_state(Example::default(), Path::new(".")).await?;
+        let requests = prometheus::IntCounter::new("requests_total", "Requests")?;
+        state.register_metrics(Box::new(requests.clone()))?;
+        requests.inc();
+
+        let next = state.reload(Path::new(".")).await?;
+        next.invalidate_state();
+
+        assert_eq!(counter(&next.clone(), "requests_total"), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_the_metrics_when_the_registry_is_replaced() -> anyhow::Result<()> {
+        let state = init_server_state(Example::default(), Path::new(".")).await?;
+        state.state().await?;
+        let start_time = state.metrics_start_time();
+
+        state.invalidate_state();
+        let invalidated = state.clone();
+        assert!(invalidated.metrics_start_time() > start_time);
+
+        let reloaded = invalidated.reload(Path::new(".")).await?;
+        assert!(reloaded.metrics_start_time() > invalidated.metrics_start_time());
+        Ok(())
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn counter(state: &ServerState<Example>, name: &str) -> u64 {
+        state
+            .metrics()
+            .gather()
+            .iter()
+            .find(|family| family.get_name() == name)
+            .map_or(0, |family| {
+                family.get_metric()[0].get_counter().get_value() as u64
+            })
+    }
+}
@@ -0,0 +1,42 @@
+//! A client for testing routers over HTTP, as they are served by the SDK.
+//!
+//! This is available to other crates with the `test-support` feature.
+
+use std::net::SocketAddr;
+
+const LOCALHOST: std::net::IpAddr = std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST);
+
+pub struct TestClient {
+    address: SocketAddr,
+    client: reqwest::Client,
+}
+
+impl TestClient {
+    pub fn new(router: axum::Router) -> anyhow::Result<Self> {
+        let listener = std::net::TcpListener::bind(std::net::SocketAddr::new(LOCALHOST, 0))?;
+        let address = listener.local_addr()?;
+
+        // we ignore the handle and let the test runner clean up the server
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .expect("server error")
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .expect("server error");
+        });
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        Ok(TestClient { address, client })
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("http://{}{}", self.address, url))
+    }
+
+    pub fn post(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.post(format!("http://{}{}", self.address, url))
+    }
+}
@@ -0,0 +1,1284 @@
+//! Validation of incoming requests, and outgoing responses, against the connector's schema.
+//!
+//! Connectors can call [`validate_query_request`] or [`validate_mutation_request`] before
+//! executing a request, to reject requests which refer to collections, fields, arguments,
+//! relationships, comparison operators or aggregate functions which are not defined in the
+//! schema returned by [`Connector::get_schema`](crate::connector::Connector::get_schema).
+//!
+//! Validation stops at the first problem found. The error carries the path to the offending
+//! part of the request in its details, for example:
+//!
+//! ```json
+//! { "path": ["query", "fields", "title", "column"] }
+//! ```
+
+use std::collections::BTreeMap;
+
+use http::StatusCode;
+use ndc_models as models;
+
+use crate::connector::{ErrorResponse, KeyOrIndex, MutationError, QueryError};
+
+static NO_ARGUMENTS: BTreeMap<models::ArgumentName, models::ArgumentInfo> = BTreeMap::new();
+
+/// Check that a query request only refers to things defined in the schema.
+pub fn validate_query_request(
+    schema: &models::SchemaResponse,
+    request: &models::QueryRequest,
+) -> std::result::Result<(), QueryError> {
+    Validator::new(schema, &request.collection_relationships)
+        .query_request(request)
+        .map_err(|err| QueryError::new_invalid_request(&err.message).with_details(err.details()))
+}
+
+/// Check that a mutation request only refers to things defined in the schema.
+pub fn validate_mutation_request(
+    schema: &models::SchemaResponse,
+    request: &models::MutationRequest,
+) -> std::result::Result<(), MutationError> {
+    Validator::new(schema, &request.collection_relationships)
+        .mutation_request(request)
+        .map_err(|err| MutationError::new_invalid_request(&err.message).with_details(err.details()))
+}
+
+/// Check that a query response matches the fields selected by the request, and the types of
+/// those fields in the schema.
+///
+/// This is intended for use during development, to catch bugs in a connector before its responses
+/// reach the engine. On failure, the error has a 500 status code, and its details contain the path
+/// to the offending value in the response, for example:
+///
+/// ```json
+/// { "path": [0, "rows", 3, "title"] }
+/// ```
+pub fn validate_query_response(
+    schema: &models::SchemaResponse,
+    request: &models::QueryRequest,
+    response: &models::QueryResponse,
+) -> crate::connector::Result<()> {
+    Validator::new(schema, &request.collection_relationships)
+        .query_response(request, response)
+        .map_err(|err| {
+            ErrorResponse::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("invalid response: {}", err.message),
+                err.details(),
+            )
+        })
+}
+
+struct ValidationError {
+    message: String,
+    path: Vec<KeyOrIndex>,
+}
+
+impl ValidationError {
+    fn details(&self) -> serde_json::Value {
+        serde_json::json!({ "path": self.path })
+    }
+}
+
+type Result<T> = std::result::Result<T, ValidationError>;
+
+/// The type of the rows being selected from.
+#[derive(Clone, Copy)]
+enum Row<'a> {
+    /// Rows of a collection, or of a nested collection, of the given object type.
+    Object(&'a models::ObjectTypeName, &'a models::ObjectType),
+    /// Rows with a single `__value` field, such as the result of a function.
+    Value(&'a models::Type),
+}
+
+struct Validator<'a> {
+    schema: &'a models::SchemaResponse,
+    collection_relationships: &'a BTreeMap<models::RelationshipName, models::Relationship>,
+    /// The path to the part of the request being validated.
+    path: Vec<KeyOrIndex>,
+    /// The rows in scope for column comparisons, which grows inside each `exists` expression.
+    scopes: Vec<Row<'a>>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(
+        schema: &'a models::SchemaResponse,
+        collection_relationships: &'a BTreeMap<models::RelationshipName, models::Relationship>,
+    ) -> Self {
+        Self {
+            schema,
+            collection_relationships,
+            path: vec![],
+            scopes: vec![],
+        }
+    }
+
+    fn error(&self, message: String) -> ValidationError {
+        ValidationError {
+            message,
+            path: self.path.clone(),
+        }
+    }
+
+    /// Validate a part of the request, found at the given path segment.
+    ///
+    /// The segment is only removed again on success, so that errors report the full path.
+    fn at<T>(&mut self, segment: KeyOrIndex, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.path.push(segment);
+        let value = f(self)?;
+        self.path.pop();
+        Ok(value)
+    }
+
+    fn query_request(&mut self, request: &models::QueryRequest) -> Result<()> {
+        let (row, arguments) = self.at(key("collection"), |v| v.collection(&request.collection))?;
+        self.at(key("arguments"), |v| {
+            v.arguments(arguments, &request.arguments)
+        })?;
+        self.at(key("query"), |v| v.query(row, &request.query))
+    }
+
+    fn mutation_request(&mut self, request: &models::MutationRequest) -> Result<()> {
+        self.at(key("operations"), |v| {
+            for (i, operation) in request.operations.iter().enumerate() {
+                v.at(index(i), |v| match operation {
+                    models::MutationOperation::Procedure {
+                        name,
+                        arguments,
+                        fields,
+                    } => {
+                        let procedure = v.at(key("name"), |v| v.procedure(name))?;
+                        v.at(key("arguments"), |v| {
+                            v.arguments(&procedure.arguments, arguments)
+                        })?;
+                        if let Some(fields) = fields {
+                            v.at(key("fields"), |v| {
+                                v.nested_field(&procedure.result_type, fields)
+                            })?;
+                        }
+                        Ok(())
+                    }
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    fn query_response(
+        &mut self,
+        request: &models::QueryRequest,
+        response: &models::QueryResponse,
+    ) -> Result<()> {
+        let (row, _) = self.collection(&request.collection)?;
+        let expected = request.variables.as_ref().map_or(1, Vec::len);
+        if response.0.len() != expected {
+            return Err(self.error(format!(
+                "expected {expected} row sets, but found {}",
+                response.0.len()
+            )));
+        }
+        for (i, row_set) in response.0.iter().enumerate() {
+            let row_set =
+                serde_json::to_value(row_set).map_err(|err| self.error(err.to_string()))?;
+            self.at(index(i), |v| v.row_set(row, &request.query, &row_set))?;
+        }
+        Ok(())
+    }
+
+    /// Validate a row set, as found in a response, against the query which produced it.
+    fn row_set(
+        &mut self,
+        row: Row<'a>,
+        query: &models::Query,
+        row_set: &serde_json::Value,
+    ) -> Result<()> {
+        if let Some(aggregates) = &query.aggregates {
+            self.at(key("aggregates"), |v| {
+                let values = v.object(&row_set["aggregates"])?;
+                v.exact_keys(values, aggregates.keys())
+            })?;
+        }
+        if let Some(fields) = &query.fields {
+            self.at(key("rows"), |v| {
+                let rows = v.array(&row_set["rows"])?;
+                for (i, row_value) in rows.iter().enumerate() {
+                    v.at(index(i), |v| {
+                        let values = v.object(row_value)?;
+                        v.exact_keys(values, fields.keys())?;
+                        for (alias, field) in fields {
+                            v.at(key(alias), |v| {
+                                v.field_value(row, field, &values[alias.as_str()])
+                            })?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn field_value(
+        &mut self,
+        row: Row<'a>,
+        field: &models::Field,
+        value: &serde_json::Value,
+    ) -> Result<()> {
+        match field {
+            models::Field::Column { column, fields, .. } => {
+                let (column_type, _) = self.field_type(row, column)?;
+                match fields {
+                    Some(fields) => self.nested_value(column_type, fields, value),
+                    None => self.typed_value(column_type, value),
+                }
+            }
+            models::Field::Relationship {
+                query,
+                relationship,
+                ..
+            } => {
+                let (_, target, _) = self.relationship(row, relationship)?;
+                self.row_set(target, query, value)
+            }
+        }
+    }
+
+    /// Validate a value against the type of a column, and the fields selected from it.
+    fn nested_value(
+        &mut self,
+        field_type: &'a models::Type,
+        nested_field: &models::NestedField,
+        value: &serde_json::Value,
+    ) -> Result<()> {
+        if value.is_null() {
+            return self.null(field_type);
+        }
+        match nested_field {
+            models::NestedField::Object(object) => {
+                let row = self.nested_object(field_type)?;
+                let values = self.object(value)?;
+                self.exact_keys(values, object.fields.keys())?;
+                for (alias, field) in &object.fields {
+                    self.at(key(alias), |v| {
+                        v.field_value(row, field, &values[alias.as_str()])
+                    })?;
+                }
+                Ok(())
+            }
+            models::NestedField::Array(array) => {
+                let element_type = self.array_element(field_type)?;
+                for (i, element) in self.array(value)?.iter().enumerate() {
+                    self.at(index(i), |v| {
+                        v.nested_value(element_type, &array.fields, element)
+                    })?;
+                }
+                Ok(())
+            }
+            models::NestedField::Collection(collection) => {
+                let element_type = self.array_element(field_type)?;
+                let row = self.nested_object(element_type)?;
+                self.row_set(row, &collection.query, value)
+            }
+        }
+    }
+
+    /// Validate a value against a type, when all of its fields are selected.
+    fn typed_value(
+        &mut self,
+        value_type: &'a models::Type,
+        value: &serde_json::Value,
+    ) -> Result<()> {
+        if value.is_null() {
+            return self.null(value_type);
+        }
+        match value_type {
+            models::Type::Nullable { underlying_type } => self.typed_value(underlying_type, value),
+            models::Type::Array { element_type } => {
+                for (i, element) in self.array(value)?.iter().enumerate() {
+                    self.at(index(i), |v| v.typed_value(element_type, element))?;
+                }
+                Ok(())
+            }
+            models::Type::Named { name } => {
+                if let Some(object_type) = self.schema.object_types.get(name.as_str()) {
+                    let values = self.object(value)?;
+                    self.exact_keys(values, object_type.fields.keys())?;
+                    for (field_name, field) in &object_type.fields {
+                        self.at(key(field_name), |v| {
+                            v.typed_value(&field.r#type, &values[field_name.as_str()])
+                        })?;
+                    }
+                    Ok(())
+                } else if let Some(scalar_type) = self.schema.scalar_types.get(name.as_str()) {
+                    if scalar_value_matches(&scalar_type.representation, value) {
+                        Ok(())
+                    } else {
+                        Err(self.error(format!(
+                            "expected a value of scalar type '{name}', but found {value}"
+                        )))
+                    }
+                } else {
+                    Err(self.error(format!("type '{name}' is not defined")))
+                }
+            }
+            models::Type::Predicate { .. } => Ok(()),
+        }
+    }
+
+    fn null(&self, value_type: &models::Type) -> Result<()> {
+        if matches!(value_type, models::Type::Nullable { .. }) {
+            Ok(())
+        } else {
+            Err(self.error("expected a value, but found null".to_owned()))
+        }
+    }
+
+    fn object<'v>(
+        &self,
+        value: &'v serde_json::Value,
+    ) -> Result<&'v serde_json::Map<String, serde_json::Value>> {
+        value
+            .as_object()
+            .ok_or_else(|| self.error(format!("expected an object, but found {value}")))
+    }
+
+    fn array<'v>(&self, value: &'v serde_json::Value) -> Result<&'v Vec<serde_json::Value>> {
+        value
+            .as_array()
+            .ok_or_else(|| self.error(format!("expected an array, but found {value}")))
+    }
+
+    /// Check that an object has exactly the expected keys.
+    fn exact_keys<'b, K: AsRef<str> + 'b>(
+        &mut self,
+        values: &serde_json::Map<String, serde_json::Value>,
+        expected: impl IntoIterator<Item = &'b K>,
+    ) -> Result<()> {
+        let expected: Vec<&str> = expected.into_iter().map(AsRef::as_ref).collect();
+        if let Some(missing) = expected.iter().find(|key| !values.contains_key(**key)) {
+            return self.at(key(missing), |v| {
+                Err(v.error("expected a value, but it is missing".to_owned()))
+            });
+        }
+        if let Some(unexpected) = values.keys().find(|key| !expected.contains(&key.as_str())) {
+            return self.at(key(unexpected), |v| {
+                Err(v.error("this value was not requested".to_owned()))
+            });
+        }
+        Ok(())
+    }
+
+    fn query(&mut self, row: Row<'a>, query: &models::Query) -> Result<()> {
+        if let Some(aggregates) = &query.aggregates {
+            self.at(key("aggregates"), |v| {
+                for (name, aggregate) in aggregates {
+                    v.at(key(name), |v| v.aggregate(row, aggregate))?;
+                }
+                Ok(())
+            })?;
+        }
+        if let Some(fields) = &query.fields {
+            self.at(key("fields"), |v| v.fields(row, fields))?;
+        }
+        if let Some(order_by) = &query.order_by {
+            self.at(key("order_by"), |v| {
+                v.at(key("elements"), |v| {
+                    for (i, element) in order_by.elements.iter().enumerate() {
+                        v.at(index(i), |v| {
+                            v.at(key("target"), |v| v.order_by_target(row, &element.target))
+                        })?;
+                    }
+                    Ok(())
+                })
+            })?;
+        }
+        if let Some(predicate) = &query.predicate {
+            self.at(key("predicate"), |v| v.scoped_expression(row, predicate))?;
+        }
+        if let Some(groups) = &query.groups {
+            self.at(key("groups"), |v| v.grouping(row, groups))?;
+        }
+        Ok(())
+    }
+
+    fn grouping(&mut self, row: Row<'a>, grouping: &models::Grouping) -> Result<()> {
+        self.at(key("dimensions"), |v| {
+            for (i, dimension) in grouping.dimensions.iter().enumerate() {
+                v.at(index(i), |v| v.dimension(row, dimension))?;
+            }
+            Ok(())
+        })?;
+        self.at(key("aggregates"), |v| {
+            for (name, aggregate) in &grouping.aggregates {
+                v.at(key(name), |v| v.aggregate(row, aggregate))?;
+            }
+            Ok(())
+        })?;
+        if let Some(predicate) = &grouping.predicate {
+            self.at(key("predicate"), |v| v.group_expression(row, predicate))?;
+        }
+        if let Some(order_by) = &grouping.order_by {
+            self.at(key("order_by"), |v| {
+                v.at(key("elements"), |v| {
+                    for (i, element) in order_by.elements.iter().enumerate() {
+                        v.at(index(i), |v| {
+                            v.at(key("target"), |v| {
+                                v.group_order_by_target(row, &grouping.dimensions, &element.target)
+                            })
+                        })?;
+                    }
+                    Ok(())
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    fn dimension(&mut self, row: Row<'a>, dimension: &models::Dimension) -> Result<()> {
+        match dimension {
+            models::Dimension::Column {
+                path,
+                column_name,
+                arguments,
+                field_path,
+                extraction,
+            } => {
+                let row = self.at(key("path"), |v| v.path(row, path))?;
+                let column_type = self.column(
+                    row,
+                    "column_name",
+                    column_name,
+                    arguments,
+                    field_path.as_deref(),
+                )?;
+                if let Some(extraction) = extraction {
+                    let (scalar_type_name, scalar_type) =
+                        self.at(key("column_name"), |v| v.scalar_type(column_type))?;
+                    if !scalar_type.extraction_functions.contains_key(extraction) {
+                        return self.at(key("extraction"), |v| {
+                            Err(v.error(format!(
+                                "extraction function '{extraction}' is not defined on scalar type '{scalar_type_name}'"
+                            )))
+                        });
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Validate a predicate on groups, whose aggregates are over the rows in each group.
+    fn group_expression(
+        &mut self,
+        row: Row<'a>,
+        expression: &models::GroupExpression,
+    ) -> Result<()> {
+        match expression {
+            models::GroupExpression::And { expressions }
+            | models::GroupExpression::Or { expressions } => self.at(key("expressions"), |v| {
+                for (i, expression) in expressions.iter().enumerate() {
+                    v.at(index(i), |v| v.group_expression(row, expression))?;
+                }
+                Ok(())
+            }),
+            models::GroupExpression::Not { expression } => {
+                self.at(key("expression"), |v| v.group_expression(row, expression))
+            }
+            models::GroupExpression::UnaryComparisonOperator {
+                target: models::GroupComparisonTarget::Aggregate { aggregate },
+                ..
+            }
+            | models::GroupExpression::BinaryComparisonOperator {
+                target: models::GroupComparisonTarget::Aggregate { aggregate },
+                ..
+            } => self.at(key("target"), |v| {
+                v.at(key("aggregate"), |v| v.aggregate(row, aggregate))
+            }),
+        }
+    }
+
+    fn group_order_by_target(
+        &mut self,
+        row: Row<'a>,
+        dimensions: &[models::Dimension],
+        target: &models::GroupOrderByTarget,
+    ) -> Result<()> {
+        match target {
+            models::GroupOrderByTarget::Dimension { index: dimension } => {
+                if *dimension >= dimensions.len() {
+                    return self.at(key("index"), |v| {
+                        Err(v.error(format!(
+                            "dimension {dimension} is not defined; there are {} dimensions",
+                            dimensions.len()
+                        )))
+                    });
+                }
+                Ok(())
+            }
+            models::GroupOrderByTarget::Aggregate { aggregate } => {
+                self.at(key("aggregate"), |v| v.aggregate(row, aggregate))
+            }
+        }
+    }
+
+    fn fields<'b>(
+        &mut self,
+        row: Row<'a>,
+        fields: impl IntoIterator<Item = (&'b models::FieldName, &'b models::Field)>,
+    ) -> Result<()> {
+        for (alias, field) in fields {
+            self.at(key(alias), |v| v.field(row, field))?;
+        }
+        Ok(())
+    }
+
+    fn field(&mut self, row: Row<'a>, field: &models::Field) -> Result<()> {
+        match field {
+            models::Field::Column {
+                column,
+                fields,
+                arguments,
+            } => {
+                let column_type = self.column(row, "column", column, arguments, None)?;
+                if let Some(fields) = fields {
+                    self.at(key("fields"), |v| v.nested_field(column_type, fields))?;
+                }
+                Ok(())
+            }
+            models::Field::Relationship {
+                query,
+                relationship,
+                arguments,
+            } => {
+                let (relationship, target, target_arguments) =
+                    self.at(key("relationship"), |v| v.relationship(row, relationship))?;
+                self.at(key("arguments"), |v| {
+                    v.relationship_arguments(target_arguments, Some(relationship), arguments)
+                })?;
+                self.at(key("query"), |v| v.query(target, query))
+            }
+        }
+    }
+
+    fn nested_field(
+        &mut self,
+        field_type: &'a models::Type,
+        nested_field: &models::NestedField,
+    ) -> Result<()> {
+        match nested_field {
+            models::NestedField::Object(object) => {
+                let row = self.nested_object(field_type)?;
+                self.at(key("fields"), |v| v.fields(row, &object.fields))
+            }
+            models::NestedField::Array(array) => {
+                let element_type = self.array_element(field_type)?;
+                self.at(key("fields"), |v| {
+                    v.nested_field(element_type, &array.fields)
+                })
+            }
+            models::NestedField::Collection(collection) => {
+                let element_type = self.array_element(field_type)?;
+                let row = self.nested_object(element_type)?;
+                self.at(key("query"), |v| v.query(row, &collection.query))
+            }
+        }
+    }
+
+    fn aggregate(&mut self, row: Row<'a>, aggregate: &models::Aggregate) -> Result<()> {
+        match aggregate {
+            models::Aggregate::ColumnCount {
+                column,
+                arguments,
+                field_path,
+                ..
+            } => {
+                self.column(row, "column", column, arguments, field_path.as_deref())?;
+                Ok(())
+            }
+            models::Aggregate::SingleColumn {
+                column,
+                arguments,
+                field_path,
+                function,
+            } => {
+                let column_type =
+                    self.column(row, "column", column, arguments, field_path.as_deref())?;
+                let (scalar_type_name, scalar_type) =
+                    self.at(key("column"), |v| v.scalar_type(column_type))?;
+                if !scalar_type.aggregate_functions.contains_key(function) {
+                    return self.at(key("function"), |v| {
+                        Err(v.error(format!(
+                            "aggregate function '{function}' is not defined on scalar type '{scalar_type_name}'"
+                        )))
+                    });
+                }
+                Ok(())
+            }
+            models::Aggregate::StarCount {} => Ok(()),
+        }
+    }
+
+    fn order_by_target(&mut self, row: Row<'a>, target: &models::OrderByTarget) -> Result<()> {
+        match target {
+            models::OrderByTarget::Column {
+                name,
+                arguments,
+                field_path,
+                path,
+            } => {
+                let row = self.at(key("path"), |v| v.path(row, path))?;
+                self.column(row, "name", name, arguments, field_path.as_deref())?;
+                Ok(())
+            }
+            models::OrderByTarget::Aggregate { aggregate, path } => {
+                let row = self.at(key("path"), |v| v.path(row, path))?;
+                self.at(key("aggregate"), |v| v.aggregate(row, aggregate))
+            }
+        }
+    }
+
+    /// Validate a predicate, which starts a new stack of scopes.
+    fn scoped_expression(&mut self, row: Row<'a>, expression: &models::Expression) -> Result<()> {
+        let outer_scopes = std::mem::replace(&mut self.scopes, vec![row]);
+        self.expression(expression)?;
+        self.scopes = outer_scopes;
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &models::Expression) -> Result<()> {
+        match expression {
+            models::Expression::And { expressions } | models::Expression::Or { expressions } => {
+                self.at(key("expressions"), |v| {
+                    for (i, expression) in expressions.iter().enumerate() {
+                        v.at(index(i), |v| v.expression(expression))?;
+                    }
+                    Ok(())
+                })
+            }
+            models::Expression::Not { expression } => {
+                self.at(key("expression"), |v| v.expression(expression))
+            }
+            models::Expression::UnaryComparisonOperator { column, .. }
+            | models::Expression::ArrayComparison { column, .. } => {
+                self.at(key("column"), |v| v.comparison_target(column))?;
+                Ok(())
+            }
+            models::Expression::BinaryComparisonOperator {
+                column,
+                operator,
+                value,
+            } => {
+                let column_type = self.at(key("column"), |v| v.comparison_target(column))?;
+                if let Some(column_type) = column_type {
+                    let (scalar_type_name, scalar_type) =
+                        self.at(key("column"), |v| v.scalar_type(column_type))?;
+                    if !scalar_type.comparison_operators.contains_key(operator) {
+                        return self.at(key("operator"), |v| {
+                            Err(v.error(format!(
+                                "comparison operator '{operator}' is not defined on scalar type '{scalar_type_name}'"
+                            )))
+                        });
+                    }
+                }
+                self.at(key("value"), |v| v.comparison_value(value))
+            }
+            models::Expression::Exists {
+                in_collection,
+                predicate,
+            } => {
+                let row = self.at(key("in_collection"), |v| {
+                    v.exists_in_collection(in_collection)
+                })?;
+                if let Some(predicate) = predicate {
+                    self.scopes.push(row);
+                    self.at(key("predicate"), |v| v.expression(predicate))?;
+                    self.scopes.pop();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Validate the target of a comparison, returning its type if it is a column.
+    fn comparison_target(
+        &mut self,
+        target: &models::ComparisonTarget,
+    ) -> Result<Option<&'a models::Type>> {
+        let row = self.current_scope();
+        match target {
+            models::ComparisonTarget::Column {
+                name,
+                arguments,
+                field_path,
+            } => self
+                .column(row, "name", name, arguments, field_path.as_deref())
+                .map(Some),
+            models::ComparisonTarget::Aggregate { aggregate, path } => {
+                let row = self.at(key("path"), |v| v.path(row, path))?;
+                self.at(key("aggregate"), |v| v.aggregate(row, aggregate))?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn comparison_value(&mut self, value: &models::ComparisonValue) -> Result<()> {
+        match value {
+            models::ComparisonValue::Column {
+                path,
+                name,
+                arguments,
+                field_path,
+                scope,
+            } => {
+                let row = self.at(key("scope"), |v| v.scope(scope.unwrap_or(0)))?;
+                let row = self.at(key("path"), |v| v.path(row, path))?;
+                self.column(row, "name", name, arguments, field_path.as_deref())?;
+                Ok(())
+            }
+            models::ComparisonValue::Scalar { .. } | models::ComparisonValue::Variable { .. } => {
+                Ok(())
+            }
+        }
+    }
+
+    fn exists_in_collection(
+        &mut self,
+        in_collection: &models::ExistsInCollection,
+    ) -> Result<Row<'a>> {
+        let row = self.current_scope();
+        match in_collection {
+            models::ExistsInCollection::Related {
+                field_path,
+                relationship,
+                arguments,
+            } => {
+                let source = self.at(key("field_path"), |v| {
+                    v.field_path(row, field_path.as_deref().unwrap_or_default())
+                })?;
+                let (relationship, target, target_arguments) = self
+                    .at(key("relationship"), |v| {
+                        v.relationship(source, relationship)
+                    })?;
+                self.at(key("arguments"), |v| {
+                    v.relationship_arguments(target_arguments, Some(relationship), arguments)
+                })?;
+                Ok(target)
+            }
+            models::ExistsInCollection::Unrelated {
+                collection,
+                arguments,
+            } => {
+                let (target, target_arguments) =
+                    self.at(key("collection"), |v| v.collection(collection))?;
+                self.at(key("arguments"), |v| {
+                    v.relationship_arguments(target_arguments, None, arguments)
+                })?;
+                Ok(target)
+            }
+            models::ExistsInCollection::NestedCollection {
+                column_name,
+                arguments,
+                field_path,
+            } => {
+                let column_type =
+                    self.column(row, "column_name", column_name, arguments, Some(field_path))?;
+                let element_type = self.array_element(column_type)?;
+                self.nested_object(element_type)
+            }
+            models::ExistsInCollection::NestedScalarCollection {
+                column_name,
+                arguments,
+                field_path,
+            } => {
+                let column_type =
+                    self.column(row, "column_name", column_name, arguments, Some(field_path))?;
+                let element_type = self.array_element(column_type)?;
+                Ok(Row::Value(element_type))
+            }
+        }
+    }
+
+    /// Follow a path of relationships, returning the rows of the final target collection.
+    fn path(&mut self, row: Row<'a>, path: &[models::PathElement]) -> Result<Row<'a>> {
+        let mut row = row;
+        for (i, element) in path.iter().enumerate() {
+            row = self.at(index(i), |v| {
+                let source = v.at(key("field_path"), |v| {
+                    v.field_path(row, element.field_path.as_deref().unwrap_or_default())
+                })?;
+                let (relationship, target, target_arguments) = v.at(key("relationship"), |v| {
+                    v.relationship(source, &element.relationship)
+                })?;
+                v.at(key("arguments"), |v| {
+                    v.relationship_arguments(
+                        target_arguments,
+                        Some(relationship),
+                        &element.arguments,
+                    )
+                })?;
+                if let Some(predicate) = &element.predicate {
+                    v.at(key("predicate"), |v| v.scoped_expression(target, predicate))?;
+                }
+                Ok(target)
+            })?;
+        }
+        Ok(row)
+    }
+
+    /// Follow a path through nested object fields, returning the innermost object.
+    fn field_path(&mut self, row: Row<'a>, field_path: &[models::FieldName]) -> Result<Row<'a>> {
+        let mut row = row;
+        for (i, field) in field_path.iter().enumerate() {
+            row = self.at(index(i), |v| {
+                let (field_type, _) = v.field_type(row, field)?;
+                v.nested_object(field_type)
+            })?;
+        }
+        Ok(row)
+    }
+
+    /// Validate a reference to a column, its arguments, and a path to a field nested within it,
+    /// returning the type of the field.
+    fn column(
+        &mut self,
+        row: Row<'a>,
+        column_key: &str,
+        column: &models::FieldName,
+        arguments: &BTreeMap<models::ArgumentName, models::Argument>,
+        field_path: Option<&[models::FieldName]>,
+    ) -> Result<&'a models::Type> {
+        let (mut column_type, expected_arguments) =
+            self.at(key(column_key), |v| v.field_type(row, column))?;
+        self.at(key("arguments"), |v| {
+            v.arguments(expected_arguments, arguments)
+        })?;
+        if let Some(field_path) = field_path {
+            self.at(key("field_path"), |v| {
+                for (i, field) in field_path.iter().enumerate() {
+                    column_type = v.at(index(i), |v| {
+                        let row = v.nested_object(column_type)?;
+                        Ok(v.field_type(row, field)?.0)
+                    })?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(column_type)
+    }
+
+    fn arguments<V>(
+        &mut self,
+        expected: &BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+        actual: &BTreeMap<models::ArgumentName, V>,
+    ) -> Result<()> {
+        self.unknown_arguments(expected, actual.keys())?;
+        self.missing_arguments(expected, |name| actual.contains_key(name))
+    }
+
+    /// Validate the arguments to a collection, which may be supplied by the relationship
+    /// definition as well as at the point of use.
+    fn relationship_arguments(
+        &mut self,
+        expected: &BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+        relationship: Option<&models::Relationship>,
+        actual: &BTreeMap<models::ArgumentName, models::RelationshipArgument>,
+    ) -> Result<()> {
+        self.unknown_arguments(expected, actual.keys())?;
+        self.missing_arguments(expected, |name| {
+            actual.contains_key(name)
+                || relationship
+                    .is_some_and(|relationship| relationship.arguments.contains_key(name))
+        })
+    }
+
+    fn unknown_arguments<'b>(
+        &mut self,
+        expected: &BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+        actual: impl IntoIterator<Item = &'b models::ArgumentName>,
+    ) -> Result<()> {
+        for name in actual {
+            if !expected.contains_key(name) {
+                return self.at(key(name), |v| {
+                    Err(v.error(format!("argument '{name}' is not defined")))
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn missing_arguments(
+        &self,
+        expected: &BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+        is_provided: impl Fn(&models::ArgumentName) -> bool,
+    ) -> Result<()> {
+        for (name, argument) in expected {
+            let is_nullable = matches!(argument.argument_type, models::Type::Nullable { .. });
+            if !is_nullable && !is_provided(name) {
+                return Err(self.error(format!("required argument '{name}' is missing")));
+            }
+        }
+        Ok(())
+    }
+
+    fn collection(
+        &self,
+        name: &models::CollectionName,
+    ) -> Result<(
+        Row<'a>,
+        &'a BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+    )> {
+        if let Some(collection) = self.schema.collections.iter().find(|c| c.name == *name) {
+            let row = self.object_type(collection.collection_type.as_str())?;
+            Ok((row, &collection.arguments))
+        } else if let Some(function) = self
+            .schema
+            .functions
+            .iter()
+            .find(|f| f.name.as_str() == name.as_str())
+        {
+            Ok((Row::Value(&function.result_type), &function.arguments))
+        } else {
+            Err(self.error(format!("collection '{name}' is not defined")))
+        }
+    }
+
+    fn procedure(&self, name: &models::ProcedureName) -> Result<&'a models::ProcedureInfo> {
+        self.schema
+            .procedures
+            .iter()
+            .find(|p| p.name == *name)
+            .ok_or_else(|| self.error(format!("procedure '{name}' is not defined")))
+    }
+
+    fn relationship(
+        &mut self,
+        source: Row<'a>,
+        name: &models::RelationshipName,
+    ) -> Result<(
+        &'a models::Relationship,
+        Row<'a>,
+        &'a BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+    )> {
+        let relationship = self
+            .collection_relationships
+            .get(name)
+            .ok_or_else(|| self.error(format!("relationship '{name}' is not defined")))?;
+        for source_column in relationship.column_mapping.keys() {
+            self.field_type(source, source_column)?;
+        }
+        let (target, target_arguments) = self.collection(&relationship.target_collection)?;
+        Ok((relationship, target, target_arguments))
+    }
+
+    fn field_type(
+        &self,
+        row: Row<'a>,
+        name: &models::FieldName,
+    ) -> Result<(
+        &'a models::Type,
+        &'a BTreeMap<models::ArgumentName, models::ArgumentInfo>,
+    )> {
+        match row {
+            Row::Object(object_type_name, object_type) => object_type
+                .fields
+                .get(name)
+                .map(|field| (&field.r#type, &field.arguments))
+                .ok_or_else(|| {
+                    self.error(format!(
+                        "field '{name}' is not defined on object type '{object_type_name}'"
+                    ))
+                }),
+            Row::Value(value_type) if name.as_str() == "__value" => Ok((value_type, &NO_ARGUMENTS)),
+            Row::Value(_) => Err(self.error(format!(
+                "field '{name}' is not defined; only '__value' can be selected"
+            ))),
+        }
+    }
+
+    fn object_type(&self, name: &str) -> Result<Row<'a>> {
+        self.schema
+            .object_types
+            .get_key_value(name)
+            .map(|(name, object_type)| Row::Object(name, object_type))
+            .ok_or_else(|| self.error(format!("object type '{name}' is not defined")))
+    }
+
+    fn nested_object(&self, field_type: &'a models::Type) -> Result<Row<'a>> {
+        match named_type(field_type) {
+            Some(name) if self.schema.object_types.contains_key(name.as_str()) => {
+                self.object_type(name.as_str())
+            }
+            _ => Err(self.error("expected a field of object type".to_owned())),
+        }
+    }
+
+    fn array_element(&self, field_type: &'a models::Type) -> Result<&'a models::Type> {
+        match strip_nullable(field_type) {
+            models::Type::Array { element_type } => Ok(element_type),
+            _ => Err(self.error("expected a field of array type".to_owned())),
+        }
+    }
+
+    fn scalar_type(
+        &self,
+        field_type: &'a models::Type,
+    ) -> Result<(&'a models::ScalarTypeName, &'a models::ScalarType)> {
+        named_type(field_type)
+            .and_then(|name| self.schema.scalar_types.get_key_value(name.as_str()))
+            .ok_or_else(|| self.error("expected a field of scalar type".to_owned()))
+    }
+
+    fn current_scope(&self) -> Row<'a> {
+        *self
+            .scopes
+            .last()
+            .expect("expressions are always validated within a scope")
+    }
+
+    fn scope(&self, scope: usize) -> Result<Row<'a>> {
+        self.scopes
+            .len()
+            .checked_sub(scope + 1)
+            .map(|i| self.scopes[i])
+            .ok_or_else(|| self.error(format!("scope {scope} is not defined")))
+    }
+}
+
+/// Check a scalar value against its representation, where that representation is known.
+fn scalar_value_matches(
+    representation: &models::TypeRepresentation,
+    value: &serde_json::Value,
+) -> bool {
+    match representation {
+        models::TypeRepresentation::Boolean => value.is_boolean(),
+        models::TypeRepresentation::String => value.is_string(),
+        models::TypeRepresentation::Int32 => value
+            .as_i64()
+            .is_some_and(|value| i32::try_from(value).is_ok()),
+        models::TypeRepresentation::Float64 => value.is_number(),
+        models::TypeRepresentation::Enum { one_of } => value
+            .as_str()
+            .is_some_and(|value| one_of.iter().any(|variant| variant == value)),
+        _ => true,
+    }
+}
+
+fn strip_nullable(field_type: &models::Type) -> &models::Type {
+    match field_type {
+        models::Type::Nullable { underlying_type } => strip_nullable(underlying_type),
+        _ => field_type,
+    }
+}
+
+fn named_type(field_type: &models::Type) -> Option<&models::TypeName> {
+    match strip_nullable(field_type) {
+        models::Type::Named { name } => Some(name),
+        _ => None,
+    }
+}
+
+fn key(key: &(impl ToString + ?Sized)) -> KeyOrIndex {
+    KeyOrIndex::Key(key.to_string())
+}
+
+fn index(index: usize) -> KeyOrIndex {
+    KeyOrIndex::Index(u32::try_from(index).unwrap_or(u32::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_models as models;
+    use serde_json::json;
+
+    use crate::connector::QueryError;
+
+    use super::{validate_query_request, validate_query_response};
+
+    fn schema() -> anyhow::Result<models::SchemaResponse> {
+        Ok(serde_json::from_value(json!({
+            "scalar_types": {
+                "Int": {
+                    "representation": { "type": "int32" },
+                    "aggregate_functions": {},
+                    "comparison_operators": { "eq": { "type": "equal" } },
+                    "extraction_functions": {}
+                },
+                "String": {
+                    "representation": { "type": "string" },
+                    "aggregate_functions": {},
+                    "comparison_operators": { "eq": { "type": "equal" } },
+                    "extraction_functions": {}
+                }
+            },
+            "object_types": {
+                "article": {
+                    "foreign_keys": {},
+                    "fields": {
+                        "id": { "type": { "type": "named", "name": "Int" } },
+                        "title": { "type": { "type": "named", "name": "String" } },
+                        "author_id": { "type": { "type": "named", "name": "Int" } }
+                    }
+                },
+                "author": {
+                    "foreign_keys": {},
+                    "fields": {
+                        "id": { "type": { "type": "named", "name": "Int" } }
+                    }
+                }
+            },
+            "collections": [
+                { "name": "articles", "arguments": {}, "type": "article", "uniqueness_constraints": {}, "foreign_keys": {} },
+                { "name": "authors", "arguments": {}, "type": "author", "uniqueness_constraints": {}, "foreign_keys": {} }
+            ],
+            "functions": [],
+            "procedures": []
+        }))?)
+    }
+
+    fn request(query: &serde_json::Value) -> anyhow::Result<models::QueryRequest> {
+        Ok(serde_json::from_value(json!({
+            "collection": "articles",
+            "arguments": {},
+            "query": query,
+            "collection_relationships": {
+                "article_author": {
+                    "column_mapping": { "author_id": ["id"] },
+                    "relationship_type": "object",
+                    "target_collection": "authors",
+                    "arguments": {}
+                }
+            }
+        }))?)
+    }
+
+    fn path_of(result: Result<(), QueryError>) -> serde_json::Value {
+        match result {
+            Err(QueryError::InvalidRequest(err)) => err.details["path"].clone(),
+            other => panic!("expected an invalid request error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_request() -> anyhow::Result<()> {
+        let request = request(&json!({
+            "fields": {
+                "title": { "type": "column", "column": "title" },
+                "author": {
+                    "type": "relationship",
+                    "relationship": "article_author",
+                    "arguments": {},
+                    "query": { "fields": { "id": { "type": "column", "column": "id" } } }
+                }
+            },
+            "predicate": {
+                "type": "binary_comparison_operator",
+                "column": { "type": "column", "name": "id" },
+                "operator": "eq",
+                "value": { "type": "scalar", "value": 1 }
+            },
+            "groups": {
+                "dimensions": [{ "type": "column", "path": [], "column_name": "author_id", "arguments": {} }],
+                "aggregates": { "count": { "type": "star_count" } },
+                "order_by": {
+                    "elements": [{ "order_direction": "asc", "target": { "type": "dimension", "index": 0 } }]
+                }
+            }
+        }))?;
+
+        assert!(validate_query_request(&schema()?, &request).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_an_unknown_column_with_its_path() -> anyhow::Result<()> {
+        let request = request(&json!({
+            "fields": {
+                "author": {
+                    "type": "relationship",
+                    "relationship": "article_author",
+                    "arguments": {},
+                    "query": { "fields": { "name": { "type": "column", "column": "name" } } }
+                }
+            }
+        }))?;
+
+        let path = path_of(validate_query_request(&schema()?, &request));
+
+        assert_eq!(
+            path,
+            json!(["query", "fields", "author", "query", "fields", "name", "column"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_an_unknown_grouping_dimension_with_its_path() -> anyhow::Result<()> {
+        let request = request(&json!({
+            "groups": {
+                "dimensions": [{
+                    "type": "column",
+                    "path": [{ "relationship": "article_author", "arguments": {} }],
+                    "column_name": "name",
+                    "arguments": {}
+                }],
+                "aggregates": {}
+            }
+        }))?;
+
+        let path = path_of(validate_query_request(&schema()?, &request));
+
+        assert_eq!(
+            path,
+            json!(["query", "groups", "dimensions", 0, "column_name"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_an_unknown_comparison_operator_with_its_path() -> anyhow::Result<()> {
+        let request = request(&json!({
+            "predicate": {
+                "type": "and",
+                "expressions": [{
+                    "type": "binary_comparison_operator",
+                    "column": { "type": "column", "name": "title" },
+                    "operator": "like",
+                    "value": { "type": "scalar", "value": "%rust%" }
+                }]
+            }
+        }))?;
+
+        let path = path_of(validate_query_request(&schema()?, &request));
+
+        assert_eq!(
+            path,
+            json!(["query", "predicate", "expressions", 0, "operator"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_a_response_with_a_mistyped_field() -> anyhow::Result<()> {
+        let request = request(&json!({
+            "fields": {
+                "id": { "type": "column", "column": "id" },
+                "title": { "type": "column", "column": "title" }
+            }
+        }))?;
+        let response: models::QueryResponse = serde_json::from_value(json!([{
+            "rows": [
+                { "id": 1, "title": "Hello" },
+                { "id": 2, "title": 42 }
+            ]
+        }]))?;
+
+        let err = validate_query_response(&schema()?, &request, &response)
+            .expect_err("expected the response to be invalid");
+
+        assert!(
+            err.to_string()
+                .contains(r#"(details: {"path":[0,"rows",1,"title"]})"#),
+            "{err}"
+        );
+        Ok(())
+    }
+}
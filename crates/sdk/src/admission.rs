@@ -0,0 +1,360 @@
+//! Admission control for the endpoints which call the connector.
+//!
+//! Requests to `/query`, `/mutation` and the explain endpoints are limited in how many may run at
+//! once, both in total and for each endpoint. Requests over the limit wait in a bounded queue, and
+//! once the queue is full, further requests are rejected with a 503 Service Unavailable and a
+//! `Retry-After` header, so that an overloaded connector sheds load rather than accumulating it.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, HttpBody as _};
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::{header, HeaderValue, Request, StatusCode};
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec};
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::connector::ErrorResponse;
+use crate::metrics::{self, Metrics};
+
+/// Limits on the number of requests which may run at once.
+///
+/// A limit which is not set allows any number of concurrent requests.
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyLimits {
+    /// The limit across `/query`, `/mutation` and the explain endpoints together.
+    pub global: Option<usize>,
+    /// The limit for `/query`.
+    pub query: Option<usize>,
+    /// The limit for `/mutation`.
+    pub mutation: Option<usize>,
+    /// The limit for `/query/explain` and `/mutation/explain` together.
+    pub explain: Option<usize>,
+    /// How many requests may wait for each limit before further requests are rejected.
+    pub max_queued: usize,
+    /// How long rejected clients are asked to wait before retrying.
+    pub retry_after: Duration,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> Self {
+        Self {
+            global: None,
+            query: None,
+            mutation: None,
+            explain: None,
+            max_queued: 100,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ConcurrencyLimits {
+    /// Whether any limit is set.
+    pub fn is_limited(&self) -> bool {
+        self.global.is_some()
+            || self.query.is_some()
+            || self.mutation.is_some()
+            || self.explain.is_some()
+    }
+}
+
+/// The state of each limit, shared by the requests they apply to.
+pub(crate) struct AdmissionControl {
+    global: Option<Limiter>,
+    query: Option<Limiter>,
+    mutation: Option<Limiter>,
+    explain: Option<Limiter>,
+    retry_after: Duration,
+    metrics: AdmissionMetrics,
+}
+
+struct Limiter {
+    name: &'static str,
+    permits: Arc<Semaphore>,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+impl AdmissionControl {
+    pub(crate) fn new(limits: ConcurrencyLimits) -> Self {
+        let limiter = |name, limit: Option<usize>| {
+            limit.map(|limit| Limiter {
+                name,
+                permits: Arc::new(Semaphore::new(limit)),
+                max_queued: limits.max_queued,
+                queued: AtomicUsize::new(0),
+            })
+        };
+        Self {
+            global: limiter("global", limits.global),
+            query: limiter("/query", limits.query),
+            mutation: limiter("/mutation", limits.mutation),
+            explain: limiter("explain", limits.explain),
+            retry_after: limits.retry_after,
+            metrics: AdmissionMetrics::new(),
+        }
+    }
+
+    /// The limits which apply to an endpoint, if it calls the connector.
+    ///
+    /// The endpoint's own limit comes first, so that requests queued for a busy endpoint do not
+    /// hold up requests to the others.
+    fn limiters(&self, path: &str) -> Option<[Option<&Limiter>; 2]> {
+        let endpoint = match path {
+            "/query" => self.query.as_ref(),
+            "/mutation" => self.mutation.as_ref(),
+            "/query/explain" | "/mutation/explain" => self.explain.as_ref(),
+            _ => return None,
+        };
+        Some([endpoint, self.global.as_ref()])
+    }
+
+    async fn acquire(&self, limiter: &Limiter) -> Result<OwnedSemaphorePermit, Response> {
+        if let Ok(permit) = limiter.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if limiter.queued.fetch_add(1, Ordering::AcqRel) >= limiter.max_queued {
+            limiter.queued.fetch_sub(1, Ordering::AcqRel);
+            return Err(self.reject(limiter));
+        }
+        // leave the queue even if the request is dropped while waiting
+        let _queued = QueueGuard::new(
+            limiter,
+            self.metrics.queue_depth.with_label_values(&[limiter.name]),
+        );
+        Ok(limiter
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("admission semaphore closed"))
+    }
+
+    fn reject(&self, limiter: &Limiter) -> Response {
+        self.metrics
+            .rejections
+            .with_label_values(&[limiter.name])
+            .inc();
+        tracing::warn!(
+            meta.signal_type = "log",
+            event.domain = "ndc",
+            event.name = "Request rejected",
+            name = "Request rejected",
+            body = format!(
+                "Too many concurrent requests for the {} limit",
+                limiter.name
+            ),
+            limit = limiter.name,
+        );
+        let retry_after = self.retry_after.as_secs().max(1);
+        let mut response = ErrorResponse::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many concurrent requests".to_owned(),
+            json!({
+                "limit": limiter.name,
+                "retryAfterSeconds": retry_after,
+            }),
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+impl Metrics for AdmissionControl {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.metrics.queue_depth.clone()),
+            Box::new(self.metrics.rejections.clone()),
+        ]
+    }
+}
+
+/// Middleware which waits for the request to be admitted by its endpoint's limit and the global
+/// limit, or rejects it if either queue is full.
+pub(crate) async fn admit(
+    State(admission): State<Arc<AdmissionControl>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(limiters) = admission.limiters(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let mut permits = Vec::with_capacity(limiters.len());
+    for limiter in limiters.into_iter().flatten() {
+        match admission.acquire(limiter).await {
+            Ok(permit) => permits.push(permit),
+            Err(response) => return response,
+        }
+    }
+    // hold the permits until the response body has been sent, or dropped if the client
+    // disconnects, as streamed responses are produced after the handler returns
+    next.run(request).await.map(|body| {
+        axum::body::boxed(body.map_data(move |data| {
+            let _ = &permits;
+            data
+        }))
+    })
+}
+
+/// A place in a limit's queue, counted in its queue depth until it is dropped.
+struct QueueGuard<'a> {
+    limiter: &'a Limiter,
+    queue_depth: IntGauge,
+}
+
+impl<'a> QueueGuard<'a> {
+    fn new(limiter: &'a Limiter, queue_depth: IntGauge) -> Self {
+        queue_depth.inc();
+        Self {
+            limiter,
+            queue_depth,
+        }
+    }
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.limiter.queued.fetch_sub(1, Ordering::AcqRel);
+        self.queue_depth.dec();
+    }
+}
+
+struct AdmissionMetrics {
+    queue_depth: IntGaugeVec,
+    rejections: IntCounterVec,
+}
+
+impl AdmissionMetrics {
+    fn new() -> Self {
+        Self {
+            queue_depth: metrics::gauge_vec(
+                "ndc_sdk_admission_queue_depth",
+                "Number of requests waiting for a concurrency limit",
+                &["limit"],
+            ),
+            rejections: metrics::counter_vec(
+                "ndc_sdk_admission_rejections_total",
+                "Number of requests rejected because a concurrency limit's queue was full",
+                &["limit"],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::{Body, Bytes};
+    use axum::response::Response;
+    use axum::routing::post;
+    use axum::{middleware, Router};
+    use http::{header, StatusCode};
+    use tokio::sync::Semaphore;
+
+    use super::{admit, AdmissionControl, ConcurrencyLimits};
+    use crate::test_support::eventually;
+    use ndc_sdk_core::test_client::TestClient;
+
+    #[tokio::test]
+    async fn holds_the_permits_until_a_streamed_body_is_sent() -> anyhow::Result<()> {
+        let admission = admission(0);
+        let bodies = Arc::new(Semaphore::new(0));
+        let client = TestClient::new(router(&admission, &bodies))?;
+
+        let streamed = client.post("/query").send().await?;
+        assert_eq!(streamed.status(), StatusCode::OK);
+
+        // the handler has returned, but its body has not been sent yet
+        let rejected = client.post("/query").send().await?;
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        bodies.add_permits(1);
+        assert_eq!(streamed.bytes().await?, "streamed");
+        eventually(|| {
+            admission
+                .query
+                .as_ref()
+                .unwrap()
+                .permits
+                .available_permits()
+                == 1
+        })
+        .await;
+
+        bodies.add_permits(1);
+        let admitted = client.post("/query").send().await?;
+        assert_eq!(admitted.status(), StatusCode::OK);
+        assert_eq!(admitted.bytes().await?, "streamed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queues_requests_and_rejects_them_once_the_queue_is_full() -> anyhow::Result<()> {
+        let admission = admission(1);
+        let bodies = Arc::new(Semaphore::new(0));
+        let client = Arc::new(TestClient::new(router(&admission, &bodies))?);
+        let queue_depth = admission.metrics.queue_depth.with_label_values(&["/query"]);
+        let rejections = admission.metrics.rejections.with_label_values(&["/query"]);
+
+        let running = client.post("/query").send().await?;
+        let queued = tokio::spawn({
+            let client = client.clone();
+            async move { client.post("/query").send().await }
+        });
+        eventually(|| queue_depth.get() == 1).await;
+
+        let rejected = client.post("/query").send().await?;
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejected.headers()[header::RETRY_AFTER], "1");
+        let body: serde_json::Value = serde_json::from_slice(&rejected.bytes().await?)?;
+        assert_eq!(body["details"]["limit"], "/query");
+        assert_eq!(body["details"]["retryAfterSeconds"], 1);
+        assert_eq!(rejections.get(), 1);
+        assert_eq!(queue_depth.get(), 1);
+
+        bodies.add_permits(2);
+        assert_eq!(running.bytes().await?, "streamed");
+        let queued = queued.await??;
+        assert_eq!(queued.status(), StatusCode::OK);
+        assert_eq!(queued.bytes().await?, "streamed");
+        assert_eq!(queue_depth.get(), 0);
+        assert_eq!(rejections.get(), 1);
+        Ok(())
+    }
+
+    fn admission(max_queued: usize) -> Arc<AdmissionControl> {
+        Arc::new(AdmissionControl::new(ConcurrencyLimits {
+            query: Some(1),
+            max_queued,
+            ..ConcurrencyLimits::default()
+        }))
+    }
+
+    /// A router whose `/query` endpoint streams its body once a permit is added to `bodies`.
+    fn router(admission: &Arc<AdmissionControl>, bodies: &Arc<Semaphore>) -> Router {
+        let bodies = bodies.clone();
+        Router::new()
+            .route(
+                "/query",
+                post(move || async move {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        bodies.acquire().await.unwrap().forget();
+                        sender.send_data(Bytes::from("streamed")).await.unwrap();
+                    });
+                    Response::new(axum::body::boxed(body))
+                }),
+            )
+            .layer(middleware::from_fn_with_state(admission.clone(), admit))
+    }
+}
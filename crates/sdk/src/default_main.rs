@@ -1,33 +1,65 @@
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 use std::{io, net};
 
 use axum::{
-    body::Body,
-    extract::State,
-    http::{HeaderValue, Request, StatusCode},
+    body::{Body, Bytes},
+    error_handling::HandleErrorLayer,
+    extract::{Extension, State},
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware,
     response::IntoResponse as _,
     routing::{get, post},
     Json,
 };
 use axum_extra::extract::WithRejection;
 use clap::{Parser, Subcommand};
-use ndc_sdk_core::schema::{get_capabilities, print_schema_and_capabilities};
+use futures_util::{Stream, StreamExt as _};
+use ndc_sdk_core::schema::print_schema_and_capabilities;
 use serde_json::json;
+use tokio_util::sync::DropGuard;
+use tower::ServiceBuilder;
 use tower_http::{
-    limit::RequestBodyLimitLayer, trace::TraceLayer, validate_request::ValidateRequestHeaderLayer,
+    compression::{
+        predicate::{And, DefaultPredicate, Predicate as _, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+    limit::RequestBodyLimitLayer,
+    trace::TraceLayer,
+    validate_request::ValidateRequestHeaderLayer,
 };
 
-use ndc_models::{
-    ExplainResponse, MutationRequest, MutationResponse, QueryRequest, QueryResponse, SchemaResponse,
-};
+use ndc_models::{ExplainResponse, MutationRequest, MutationResponse, QueryRequest, QueryResponse};
 
+use crate::admission::{admit, AdmissionControl, ConcurrencyLimits};
+use crate::capabilities::{
+    check_mutation_capabilities, check_mutation_explain_capabilities, check_query_capabilities,
+    check_query_explain_capabilities,
+};
 use crate::check_health;
 use crate::connector::{Connector, ConnectorSetup, ErrorResponse, Result};
-use crate::fetch_metrics::fetch_metrics;
+use crate::fetch_metrics::{fetch_metrics, MetricsFormat};
+use crate::health::HealthReport;
+use crate::http_metrics::{record_http_metrics, HttpMetrics};
 use crate::json_rejection::JsonRejection;
-use crate::json_response::JsonResponse;
-use crate::state::{init_server_state, ServerState};
-use crate::tracing::{init_tracing, make_span, on_response};
+use crate::json_response::{JsonResponse, JsonStream};
+use crate::metrics::Metrics;
+use crate::query_cache::{query_cache_ttl, CacheKey, QueryCache};
+use crate::query_coalescing::QueryCoalescer;
+use crate::rate_limit::{self, RateLimit, RateLimiter};
+use crate::reload;
+use crate::request_context::RequestContext;
+use crate::state::{init_server_state, Backoff, ReloadableServerState, ServerState};
+use crate::tracing::{
+    connector_resource, init_metrics_export, init_tracing, make_span, on_response,
+    shutdown_tracing, Exporter, TracesSampler,
+};
+use crate::validation::validate_query_response;
 
 #[derive(Parser)]
 struct CliArgs {
@@ -36,6 +68,7 @@ struct CliArgs {
 }
 
 #[derive(Clone, Subcommand)]
+#[allow(clippy::large_enum_variant)] // parsed once, on startup
 enum Command {
     #[command()]
     Serve(ServeCommand),
@@ -55,11 +88,43 @@ enum Command {
 }
 
 #[derive(Clone, Parser)]
+#[allow(clippy::struct_excessive_bools)] // each is a command-line flag
 struct ServeCommand {
     #[arg(long, value_name = "DIRECTORY", env = "HASURA_CONFIGURATION_DIRECTORY")]
     configuration: PathBuf,
     #[arg(long, value_name = "ENDPOINT", env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
     otlp_endpoint: Option<String>,
+    #[arg(
+        long,
+        value_name = "EXPORTER",
+        env = "OTEL_METRICS_EXPORTER",
+        default_value = "none",
+        help = "Periodically export metrics over OTLP to the OTLP endpoint, in addition to serving them on /metrics, if set to otlp"
+    )]
+    metrics_exporter: String,
+    #[arg(
+        long,
+        value_name = "EXPORTER",
+        env = "OTEL_LOGS_EXPORTER",
+        default_value = "none",
+        help = "Export logs over OTLP to the OTLP endpoint, in addition to writing them to stdout, if set to otlp"
+    )]
+    logs_exporter: String,
+    #[arg(
+        long,
+        value_name = "SAMPLER",
+        env = "OTEL_TRACES_SAMPLER",
+        default_value = "parentbased_always_on",
+        help = "Which traces to export: always_on, always_off, traceidratio, or one of these prefixed with parentbased_"
+    )]
+    traces_sampler: String,
+    #[arg(
+        long,
+        value_name = "RATIO",
+        env = "OTEL_TRACES_SAMPLER_ARG",
+        help = "The ratio of traces to export, between 0 and 1, for the traceidratio samplers"
+    )]
+    traces_sampler_arg: Option<String>,
     #[arg(
         long,
         value_name = "HOST IP",
@@ -81,6 +146,136 @@ struct ServeCommand {
     service_name: Option<String>,
     #[arg(long, value_name = "MAX_REQUEST_SIZE", env = "HASURA_MAX_REQUEST_SIZE")]
     max_request_size: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HASURA_QUERY_TIMEOUT",
+        value_parser = parse_seconds
+    )]
+    query_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HASURA_MUTATION_TIMEOUT",
+        value_parser = parse_seconds
+    )]
+    mutation_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HASURA_EXPLAIN_TIMEOUT",
+        value_parser = parse_seconds
+    )]
+    explain_timeout: Option<Duration>,
+    #[arg(
+        long,
+        env = "HASURA_WATCH_CONFIGURATION",
+        help = "Reload the configuration when files in the configuration directory change"
+    )]
+    watch_configuration: bool,
+    #[arg(
+        long,
+        env = "HASURA_INITIALIZE_STATE_ON_STARTUP",
+        help = "Initialize the connector state in the background on startup, retrying until it succeeds"
+    )]
+    initialize_state_on_startup: bool,
+    #[arg(
+        long,
+        value_name = "MODE",
+        env = "HASURA_VALIDATE_RESPONSES",
+        num_args = 0..=1,
+        default_missing_value = "fail",
+        help = "Check query responses against the request and the schema, and log or fail on invalid responses (for development)"
+    )]
+    validate_responses: Option<ResponseValidation>,
+    #[arg(
+        long,
+        env = "HASURA_DISABLE_COMPRESSION",
+        help = "Do not compress response bodies"
+    )]
+    disable_compression: bool,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "HASURA_COMPRESSION_MIN_SIZE",
+        default_value_t = ResponseCompression::default().min_size,
+        help = "Do not compress response bodies smaller than this size"
+    )]
+    compression_min_size: u16,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "HASURA_QUERY_CACHE_SIZE",
+        help = "Cache responses to queries against collections which the connector allows to be cached, using up to this much memory"
+    )]
+    query_cache_size: Option<usize>,
+    #[arg(
+        long,
+        env = "HASURA_COALESCE_QUERIES",
+        help = "Run identical concurrent queries once, sharing the response between them"
+    )]
+    coalesce_queries: bool,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_MAX_CONCURRENT_REQUESTS",
+        help = "The maximum number of requests to /query, /mutation and the explain endpoints which may run at once"
+    )]
+    max_concurrent_requests: Option<usize>,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_MAX_CONCURRENT_QUERIES",
+        help = "The maximum number of requests to /query which may run at once"
+    )]
+    max_concurrent_queries: Option<usize>,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_MAX_CONCURRENT_MUTATIONS",
+        help = "The maximum number of requests to /mutation which may run at once"
+    )]
+    max_concurrent_mutations: Option<usize>,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_MAX_CONCURRENT_EXPLAINS",
+        help = "The maximum number of requests to /query/explain and /mutation/explain which may run at once"
+    )]
+    max_concurrent_explains: Option<usize>,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_MAX_QUEUED_REQUESTS",
+        default_value_t = ConcurrencyLimits::default().max_queued,
+        help = "The maximum number of requests which may wait for each concurrency limit before further requests are rejected"
+    )]
+    max_queued_requests: usize,
+    #[arg(
+        long,
+        value_name = "REQUESTS_PER_SECOND",
+        env = "HASURA_RATE_LIMIT",
+        value_parser = parse_rate,
+        help = "The rate at which each client may make requests, identified by its token if a service token secret is set, and by its address otherwise"
+    )]
+    rate_limit: Option<f64>,
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        env = "HASURA_RATE_LIMIT_BURST",
+        requires = "rate_limit",
+        help = "The number of requests each client may make at once, which defaults to one second's worth"
+    )]
+    rate_limit_burst: Option<u32>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HASURA_FETCH_METRICS_TIMEOUT",
+        value_parser = parse_seconds,
+        default_value = "5",
+        help = "How long the connector may take to update its metrics on a request to /metrics, after which the previous values are served"
+    )]
+    fetch_metrics_timeout: Duration,
 }
 
 #[derive(Clone, Parser)]
@@ -147,6 +342,111 @@ struct CheckHealthCommand {
 
 type Port = u16;
 
+fn parse_seconds(value: &str) -> std::result::Result<Duration, String> {
+    let seconds = value.parse::<f64>().map_err(|err| err.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
+
+fn parse_rate(value: &str) -> std::result::Result<f64, String> {
+    let rate = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err("must be a positive number".to_owned())
+    }
+}
+
+/// Deadlines for requests to each endpoint. Requests which exceed their deadline fail with a
+/// 504 Gateway Timeout, and streamed responses which are still being sent at the deadline are
+/// aborted.
+///
+/// If a deadline is not set, requests to that endpoint may run indefinitely.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTimeouts {
+    /// The deadline for `/query`.
+    pub query: Option<Duration>,
+    /// The deadline for `/mutation`.
+    pub mutation: Option<Duration>,
+    /// The deadline for `/query/explain` and `/mutation/explain`.
+    pub explain: Option<Duration>,
+}
+
+/// What to do when a query response does not match the request or the schema.
+///
+/// Validating responses requires each response to be buffered in memory, so it is intended for
+/// development and staging environments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResponseValidation {
+    /// Log an error, and return the response as is.
+    Log,
+    /// Log an error, and fail the request with a 500 Internal Server Error.
+    Fail,
+}
+
+/// Compression of response bodies, using gzip, brotli or zstd as negotiated with the client
+/// through the `Accept-Encoding` header.
+#[derive(Clone, Copy, Debug)]
+pub struct ResponseCompression {
+    /// Response bodies smaller than this many bytes are sent uncompressed, as compressing them is
+    /// not worth the overhead. Bodies whose size is not known in advance, such as streamed
+    /// responses, are always compressed.
+    pub min_size: u16,
+}
+
+impl Default for ResponseCompression {
+    fn default() -> Self {
+        Self { min_size: 1024 }
+    }
+}
+
+/// The behaviour of the router built by [`create_router`].
+///
+/// The default options match those of `serve` when no flags are given.
+pub struct RouterOptions {
+    /// If set, requests other than health checks must carry this secret as a bearer token.
+    pub service_token_secret: Option<String>,
+    /// The maximum size of a request body, after decompression. Defaults to 100MB.
+    pub max_request_size: Option<usize>,
+    /// Deadlines for requests to each endpoint.
+    pub request_timeouts: RequestTimeouts,
+    /// Whether to validate query responses, and what to do with invalid ones.
+    pub response_validation: Option<ResponseValidation>,
+    /// How to compress response bodies, if at all.
+    pub response_compression: Option<ResponseCompression>,
+    /// A cache of query responses, for collections which the connector allows to be cached.
+    pub query_cache: Option<QueryCache>,
+    /// Whether to run identical concurrent queries once, sharing the response between them.
+    pub coalesce_queries: bool,
+    /// Limits on the number of requests which may call the connector at once.
+    pub concurrency_limits: ConcurrencyLimits,
+    /// The rate at which each client may make requests, if limited.
+    ///
+    /// Clients are identified by the token they present if a service token secret is set.
+    /// Otherwise, they are identified by their address, so the router must be served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()` for each to be limited separately.
+    pub rate_limit: Option<RateLimit>,
+    /// How long the connector may take to update its metrics on a request to `/metrics`. Defaults
+    /// to 5 seconds.
+    pub fetch_metrics_timeout: Duration,
+}
+
+impl Default for RouterOptions {
+    fn default() -> Self {
+        Self {
+            service_token_secret: None,
+            max_request_size: None,
+            request_timeouts: RequestTimeouts::default(),
+            response_validation: None,
+            response_compression: Some(ResponseCompression::default()),
+            query_cache: None,
+            coalesce_queries: false,
+            concurrency_limits: ConcurrencyLimits::default(),
+            rate_limit: None,
+            fetch_metrics_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
 /// A default main function for a connector.
 ///
 /// The intent is that this function can replace your `main` function
@@ -217,24 +517,75 @@ where
     <Setup::Connector as Connector>::Configuration: Clone,
     <Setup::Connector as Connector>::State: Clone,
 {
-    init_tracing(
-        serve_command.service_name.as_deref(),
-        serve_command.otlp_endpoint.as_deref(),
-    )
-    .expect("Unable to initialize tracing");
+    let resource = init_telemetry(&setup, &serve_command);
+
+    let server_state =
+        ReloadableServerState::from(init_server_state(setup, &serve_command.configuration).await?);
+    let shutdown_token = server_state.current().shutdown_token().clone();
+
+    let meter_provider = init_metrics_exporter(&serve_command, &resource, &server_state);
+
+    if serve_command.initialize_state_on_startup {
+        server_state
+            .current()
+            .init_state_in_background(Backoff::default());
+    }
 
-    let server_state = init_server_state(setup, &serve_command.configuration).await?;
+    // reload the configuration on a SIGHUP, i.e. `kill -HUP`
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_sighup(
+        server_state.clone(),
+        serve_command.configuration.clone(),
+    ));
+    if serve_command.watch_configuration {
+        tokio::spawn(reload::watch_configuration(
+            server_state.clone(),
+            serve_command.configuration.clone(),
+        ));
+    }
 
     let router = create_router::<Setup::Connector>(
         server_state,
-        serve_command.service_token_secret,
-        serve_command.max_request_size,
+        RouterOptions {
+            service_token_secret: serve_command.service_token_secret,
+            max_request_size: serve_command.max_request_size,
+            request_timeouts: RequestTimeouts {
+                query: serve_command.query_timeout,
+                mutation: serve_command.mutation_timeout,
+                explain: serve_command.explain_timeout,
+            },
+            response_validation: serve_command.validate_responses,
+            response_compression: (!serve_command.disable_compression).then_some(
+                ResponseCompression {
+                    min_size: serve_command.compression_min_size,
+                },
+            ),
+            query_cache: serve_command.query_cache_size.map(QueryCache::new),
+            coalesce_queries: serve_command.coalesce_queries,
+            concurrency_limits: ConcurrencyLimits {
+                global: serve_command.max_concurrent_requests,
+                query: serve_command.max_concurrent_queries,
+                mutation: serve_command.max_concurrent_mutations,
+                explain: serve_command.max_concurrent_explains,
+                max_queued: serve_command.max_queued_requests,
+                ..ConcurrencyLimits::default()
+            },
+            rate_limit: serve_command
+                .rate_limit
+                .map(|requests_per_second| RateLimit {
+                    requests_per_second,
+                    burst: serve_command
+                        .rate_limit_burst
+                        .unwrap_or_else(|| default_burst(requests_per_second)),
+                }),
+            fetch_metrics_timeout: serve_command.fetch_metrics_timeout,
+        },
     );
 
     let address = net::SocketAddr::new(serve_command.host, serve_command.port);
     println!("Starting server on {address}");
     axum::Server::bind(&address)
-        .serve(router.into_make_service())
+        .serve(router.into_make_service_with_connect_info::<net::SocketAddr>())
         .with_graceful_shutdown(async {
             // wait for a SIGINT, i.e. a Ctrl+C from the keyboard
             let sigint = async {
@@ -261,24 +612,157 @@ where
                 _ = sigint => (),
             }
 
-            opentelemetry::global::shutdown_tracer_provider();
+            // abort any in-flight connector work before waiting for it to finish
+            shutdown_token.cancel();
+
+            shutdown_tracing();
         })
         .await
         .map_err(ErrorResponse::from_error)?;
 
+    shutdown_metrics_exporter(meter_provider).await
+}
+
+/// Set up logging and the export of traces and logs, returning the resource which describes the
+/// connector in its telemetry.
+fn init_telemetry(
+    setup: &impl ConnectorSetup,
+    serve_command: &ServeCommand,
+) -> opentelemetry_sdk::Resource {
+    let resource = connector_resource(setup, serve_command.service_name.as_deref());
+    let (logs_exporter, logs_exporter_warning) = Exporter::parse(&serve_command.logs_exporter);
+    let (sampler, sampler_warning) = TracesSampler::parse(
+        &serve_command.traces_sampler,
+        serve_command.traces_sampler_arg.as_deref(),
+    );
+    init_tracing(
+        &resource,
+        serve_command.otlp_endpoint.as_deref(),
+        logs_exporter,
+        sampler,
+    )
+    .expect("Unable to initialize tracing");
+    // settings which are ignored can only be reported once logging is set up
+    if let Some(warning) = logs_exporter_warning {
+        warn_unsupported_setting("OTEL_LOGS_EXPORTER", &warning);
+    }
+    if let Some((variable, warning)) = sampler_warning {
+        warn_unsupported_setting(variable, &warning);
+    }
+    resource
+}
+
+/// Start exporting metrics over OTLP, if configured to.
+///
+/// Metrics are gathered from the current state, so that they follow configuration reloads, and
+/// count from when its metrics registry was created.
+fn init_metrics_exporter<C>(
+    serve_command: &ServeCommand,
+    resource: &opentelemetry_sdk::Resource,
+    server_state: &ReloadableServerState<C>,
+) -> Option<opentelemetry_sdk::metrics::SdkMeterProvider>
+where
+    C: Connector + 'static,
+    C::Configuration: Clone,
+{
+    let (metrics_exporter, warning) = Exporter::parse(&serve_command.metrics_exporter);
+    if let Some(warning) = warning {
+        warn_unsupported_setting("OTEL_METRICS_EXPORTER", &warning);
+    }
+    match metrics_exporter {
+        Exporter::Otlp => {
+            let server_state = server_state.clone();
+            init_metrics_export(
+                resource,
+                serve_command.otlp_endpoint.as_deref(),
+                move || {
+                    let state = server_state.current();
+                    (state.metrics_start_time(), state.metrics().gather())
+                },
+            )
+            .expect("Unable to initialize metrics export")
+        }
+        Exporter::None => None,
+    }
+}
+
+/// Warn that a telemetry setting is not supported, and has been ignored in favour of the default.
+fn warn_unsupported_setting(variable: &str, warning: &str) {
+    tracing::warn!(
+        meta.signal_type = "log",
+        event.domain = "ndc",
+        event.name = "Unsupported telemetry setting",
+        name = "Unsupported telemetry setting",
+        body = format!("{variable}: {warning}"),
+    );
+}
+
+/// Export the final values of the metrics, if they are exported over OTLP.
+async fn shutdown_metrics_exporter(
+    meter_provider: Option<opentelemetry_sdk::metrics::SdkMeterProvider>,
+) -> Result<()> {
+    if let Some(meter_provider) = meter_provider {
+        // shutting down blocks until the export completes
+        let result = tokio::task::spawn_blocking(move || meter_provider.shutdown())
+            .await
+            .map_err(ErrorResponse::from_error)?;
+        if let Err(err) = result {
+            tracing::warn!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Unable to export metrics",
+                name = "Unable to export metrics",
+                body = %err,
+            );
+        }
+    }
     Ok(())
 }
 
 pub fn create_router<C>(
-    state: ServerState<C>,
-    service_token_secret: Option<String>,
-    max_request_size: Option<usize>,
+    state: impl Into<ReloadableServerState<C>>,
+    RouterOptions {
+        service_token_secret,
+        max_request_size,
+        request_timeouts,
+        response_validation,
+        response_compression,
+        query_cache,
+        coalesce_queries,
+        concurrency_limits,
+        rate_limit,
+        fetch_metrics_timeout,
+    }: RouterOptions,
 ) -> axum::Router<()>
 where
     C: Connector + 'static,
     C::Configuration: Clone,
     C::State: Clone,
 {
+    let state = state.into();
+    if let Some(query_cache) = &query_cache {
+        register_metrics(&state.current(), query_cache);
+    }
+    let query_coalescer = coalesce_queries.then(QueryCoalescer::new);
+    if let Some(query_coalescer) = &query_coalescer {
+        register_metrics(&state.current(), query_coalescer);
+    }
+    let admission = Arc::new(AdmissionControl::new(concurrency_limits));
+    if concurrency_limits.is_limited() {
+        register_metrics(&state.current(), admission.as_ref());
+    }
+    let rate_limiter = rate_limit.map(|rate_limit| {
+        Arc::new(RateLimiter::new(
+            rate_limit,
+            service_token_secret.as_deref(),
+        ))
+    });
+    if let Some(rate_limiter) = &rate_limiter {
+        register_metrics(&state.current(), rate_limiter.as_ref());
+    }
+    let http_metrics = Arc::new(HttpMetrics::new());
+    register_metrics(&state.current(), http_metrics.as_ref());
+
     axum::Router::new()
         .route("/capabilities", get(get_capabilities::<C>))
         .route("/metrics", get(get_metrics::<C>))
@@ -293,12 +777,57 @@ where
         .layer(RequestBodyLimitLayer::new(
             max_request_size.unwrap_or(100 * 1024 * 1024),
         ))
+        // Request bodies may be compressed with gzip or zstd. They are decompressed outside the
+        // request size limit, so that the limit applies to the decompressed body, and a small
+        // compressed body cannot expand to an unbounded size. Bodies with other encodings are
+        // rejected with a 415 Unsupported Media Type, listing those supported in the
+        // `Accept-Encoding` header.
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::map_response(unsupported_encoding))
+                .layer(HandleErrorLayer::new(|err: axum::BoxError| async move {
+                    ErrorResponse::from(err)
+                }))
+                .layer(
+                    RequestDecompressionLayer::new()
+                        .gzip(true)
+                        .zstd(true)
+                        .no_br()
+                        .no_deflate(),
+                ),
+        )
+        // Requests are queued for admission after they are authenticated, so that
+        // unauthenticated requests cannot take up places in the queue.
+        .layer(middleware::from_fn_with_state(admission, admit))
         .layer(ValidateRequestHeaderLayer::custom(auth_handler(
             service_token_secret,
         )))
         .layer(ValidateRequestHeaderLayer::custom(check_version_header))
-        .route("/health", get(get_health_readiness::<C>)) // health checks are not authenticated
+        // Requests are rate limited before they are authenticated, so that clients presenting
+        // invalid tokens are limited too.
+        .layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::rate_limit,
+        ))
+        .layer(Extension(request_timeouts))
+        .layer(Extension(FetchMetricsTimeout(fetch_metrics_timeout)))
+        .layer(Extension(http_metrics.clone()))
+        .layer(Extension(response_validation))
+        .layer(Extension(query_cache.map(Arc::new)))
+        .layer(Extension(query_coalescer.map(Arc::new)))
+        // health checks are not authenticated
+        .route("/health", get(get_health_readiness::<C>))
+        .route("/health/live", get(get_health_live))
+        .route("/health/ready", get(get_health_ready::<C>))
+        .route("/health/connected", get(get_health_connected::<C>))
+        // Metrics are recorded around every other layer, so that rejected requests are counted,
+        // but inside compression, so that response sizes are comparable across clients.
+        .layer(middleware::from_fn_with_state(
+            http_metrics,
+            record_http_metrics,
+        ))
         .with_state(state)
+        .layer(compression_layer(response_compression))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(make_span)
@@ -316,6 +845,77 @@ where
         )
 }
 
+/// One second's worth of requests, and at least one.
+fn default_burst(requests_per_second: f64) -> u32 {
+    // the rate is positive and finite, and saturates when converted
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let burst = requests_per_second.ceil() as u32;
+    burst.max(1)
+}
+
+fn register_metrics<C: Connector>(state: &ServerState<C>, metrics: &dyn Metrics) {
+    for collector in metrics.collectors() {
+        if let Err(err) = state.register_metrics(collector) {
+            tracing::warn!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Unable to register metrics",
+                name = "Unable to register metrics",
+                body = %err,
+            );
+        }
+    }
+}
+
+fn compression_layer(
+    response_compression: Option<ResponseCompression>,
+) -> CompressionLayer<And<DefaultPredicate, SizeAbove>> {
+    let enabled = response_compression.is_some();
+    let min_size = response_compression.map_or(0, |compression| compression.min_size);
+    // JSON is compressed directly from the serialized bytes, without being parsed again. The
+    // default predicate leaves content which is already compressed, or which is streamed to the
+    // client as it is produced, such as images, gRPC and event streams, uncompressed.
+    CompressionLayer::new()
+        .gzip(enabled)
+        .br(enabled)
+        .zstd(enabled)
+        .compress_when(DefaultPredicate::new().and(SizeAbove::new(min_size)))
+}
+
+/// Give the response to a request body compressed with an unsupported encoding, which is produced
+/// by the decompression layer without a body, the same form as other errors.
+///
+/// The response is told apart from other 415 Unsupported Media Type responses by its
+/// `Accept-Encoding` header, which lists the supported encodings, and which is kept.
+async fn unsupported_encoding(response: axum::response::Response) -> axum::response::Response {
+    if response.status() != StatusCode::UNSUPPORTED_MEDIA_TYPE {
+        return response;
+    }
+    let Some(accept_encoding) = response
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .cloned()
+    else {
+        return response;
+    };
+    let supported_encodings = accept_encoding
+        .to_str()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let mut response = ErrorResponse::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "Unsupported request body encoding".to_owned(),
+        json!({ "supportedEncodings": supported_encodings }),
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(http::header::ACCEPT_ENCODING, accept_encoding);
+    response
+}
+
 fn auth_handler(
     service_token_secret: Option<String>,
 ) -> impl Fn(&mut Request<Body>) -> std::result::Result<(), axum::response::Response> + Clone {
@@ -408,46 +1008,435 @@ fn check_version_header(
     Ok(())
 }
 
-async fn get_metrics<C: Connector>(State(state): State<ServerState<C>>) -> Result<String> {
-    fetch_metrics::<C>(state.configuration(), state.state().await?, state.metrics())
+/// How long the connector may take to update its metrics, as a distinct type so that it can be
+/// passed to handlers as an extension.
+#[derive(Clone, Copy)]
+struct FetchMetricsTimeout(Duration);
+
+/// Serve the metrics in the format the client asks for, which carries the exemplars of the HTTP
+/// metrics if it is OpenMetrics.
+async fn get_metrics<C: Connector>(
+    State(state): State<ServerState<C>>,
+    Extension(FetchMetricsTimeout(timeout)): Extension<FetchMetricsTimeout>,
+    Extension(http_metrics): Extension<Arc<HttpMetrics>>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    let format = MetricsFormat::negotiate(headers.get(http::header::ACCEPT));
+    let body = fetch_metrics::<C>(
+        state.configuration(),
+        state.state().await?,
+        state.metrics(),
+        timeout,
+        format,
+        http_metrics.as_ref(),
+    )
+    .await?;
+    Ok((
+        [(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static(format.content_type()),
+        )],
+        body,
+    )
+        .into_response())
 }
 
 async fn get_health_readiness<C: Connector>(State(state): State<ServerState<C>>) -> Result<()> {
-    C::get_health_readiness(state.configuration(), state.state().await?).await
+    C::get_health_readiness(state.configuration(), health_check_state(&state).await?).await
+}
+
+/// Get the connector state for a health check.
+///
+/// If the state is being initialized in the background, we are not ready until that succeeds,
+/// so we fail rather than initializing it here.
+async fn health_check_state<C: Connector>(state: &ServerState<C>) -> Result<&C::State> {
+    if state.is_initializing_in_background() {
+        return Err(ErrorResponse::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Connector state is not yet initialized".to_owned(),
+            serde_json::Value::Null,
+        ));
+    }
+    state.state().await
+}
+
+/// The server is running and able to respond.
+async fn get_health_live() -> HealthReport {
+    HealthReport::new()
+}
+
+/// The connector state is initialized and the connector is ready to accept requests.
+async fn get_health_ready<C: Connector>(State(state): State<ServerState<C>>) -> HealthReport {
+    let mut report = HealthReport::new();
+    check_readiness(&state, &mut report).await;
+    report
+}
+
+/// The connector is ready, and can reach the external services it depends on.
+async fn get_health_connected<C: Connector>(State(state): State<ServerState<C>>) -> HealthReport {
+    let mut report = HealthReport::new();
+    let connector_state = check_readiness(&state, &mut report).await;
+    report
+        .check("connectivity", async {
+            match connector_state {
+                Some(connector_state) => {
+                    C::check_connectivity(state.configuration(), connector_state).await
+                }
+                None => Ok(()), // not run, as an earlier check failed
+            }
+        })
+        .await;
+    report
+}
+
+async fn check_readiness<'a, C: Connector>(
+    state: &'a ServerState<C>,
+    report: &mut HealthReport,
+) -> Option<&'a C::State> {
+    let connector_state = report.check("state", health_check_state(state)).await?;
+    report
+        .check(
+            "readiness",
+            C::get_health_readiness(state.configuration(), connector_state),
+        )
+        .await?;
+    Some(connector_state)
+}
+
+async fn get_capabilities<C: Connector>(
+    State(state): State<ServerState<C>>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    Ok(state.capabilities().await?.respond_to(&headers))
 }
 
 async fn get_schema<C: Connector>(
     State(state): State<ServerState<C>>,
-) -> Result<JsonResponse<SchemaResponse>> {
-    C::get_schema(state.configuration()).await
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    Ok(state.schema().await?.respond_to(&headers))
+}
+
+fn make_request_context<C: Connector>(
+    state: &ServerState<C>,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+) -> RequestContext {
+    let request_context =
+        RequestContext::new(headers).with_cancellation_token(state.shutdown_token().child_token());
+    match timeout {
+        None => request_context,
+        Some(timeout) => request_context.with_deadline(Instant::now() + timeout),
+    }
+}
+
+/// Await the handler, failing with a 504 Gateway Timeout if the request's deadline passes first.
+///
+/// The request's cancellation token is cancelled if the handler does not run to completion, either
+/// because the deadline passed or because the handler was dropped, which happens when the client
+/// disconnects. A streamed response is bound by the same deadline: if it has not ended by then, it
+/// ends with an error, aborting the response. It cancels the token in either case, or if it is
+/// dropped before it has been sent in full.
+///
+/// If the handler fails with an error which invalidates the connector state, the state is dropped.
+async fn run_request<C: Connector, T>(
+    state: &ServerState<C>,
+    request_context: &RequestContext,
+    endpoint: &'static str,
+    timeout: Option<Duration>,
+    handler: impl Future<Output = Result<JsonResponse<T>>>,
+) -> Result<JsonResponse<T>> {
+    let guard = request_context.cancellation_token().clone().drop_guard();
+    let result = match request_context.deadline() {
+        None => Ok(handler.await),
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), handler).await,
+    };
+    match result {
+        Ok(Ok(JsonResponse::Streamed(stream))) => Ok(JsonResponse::from_stream(RequestStream {
+            stream: Some(stream),
+            guard: Some(guard),
+            deadline: request_context
+                .deadline()
+                .map(|deadline| Box::pin(tokio::time::sleep_until(deadline.into()))),
+            endpoint,
+            timeout: timeout.unwrap_or_default(),
+        })),
+        Ok(result) => {
+            guard.disarm();
+            if let Err(err) = &result {
+                if err.invalidates_state() {
+                    tracing::error!(
+                        meta.signal_type = "log",
+                        event.domain = "ndc",
+                        event.name = "Connector state invalidated",
+                        name = "Connector state invalidated",
+                        body = %err,
+                        error = true,
+                    );
+                    state.invalidate_state();
+                }
+            }
+            result
+        }
+        Err(_elapsed) => {
+            let timeout = timeout.unwrap_or_default();
+            log_deadline_exceeded(endpoint, timeout);
+            Err(ErrorResponse::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Request deadline exceeded".to_owned(),
+                json!({
+                    "endpoint": endpoint,
+                    "timeoutMillis": timeout.as_millis(),
+                }),
+            ))
+        }
+    }
+}
+
+fn log_deadline_exceeded(endpoint: &str, timeout: Duration) {
+    tracing::error!(
+        meta.signal_type = "log",
+        event.domain = "ndc",
+        event.name = "Request deadline exceeded",
+        name = "Request deadline exceeded",
+        body = format!("{endpoint} did not complete within {timeout:?}"),
+        error = true,
+    );
+}
+
+/// A streamed response, which cancels its request if it is dropped before it has ended, and which
+/// ends with an error if the request's deadline passes first.
+struct RequestStream {
+    // dropped once the deadline has passed
+    stream: Option<JsonStream>,
+    // disarmed once the stream has ended
+    guard: Option<DropGuard>,
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    endpoint: &'static str,
+    timeout: Duration,
+}
+
+impl Stream for RequestStream {
+    type Item = std::result::Result<Bytes, axum::BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(stream) = &mut this.stream else {
+            return Poll::Ready(None);
+        };
+        if let Some(deadline) = &mut this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                log_deadline_exceeded(this.endpoint, this.timeout);
+                // dropping the guard cancels the request
+                this.stream = None;
+                this.guard = None;
+                return Poll::Ready(Some(Err(format!(
+                    "{} did not complete within {:?}",
+                    this.endpoint, this.timeout
+                )
+                .into())));
+            }
+        }
+        let item = ready!(stream.poll_next_unpin(cx));
+        if item.is_none() {
+            if let Some(guard) = this.guard.take() {
+                guard.disarm();
+            }
+        }
+        Poll::Ready(item)
+    }
 }
 
 async fn post_query_explain<C: Connector>(
     State(state): State<ServerState<C>>,
+    Extension(timeouts): Extension<RequestTimeouts>,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, JsonRejection>,
 ) -> Result<JsonResponse<ExplainResponse>> {
-    C::query_explain(state.configuration(), state.state().await?, request).await
+    check_query_explain_capabilities(&state.parsed_capabilities().await?.capabilities, &request)?;
+    let request_context = make_request_context(&state, headers, timeouts.explain);
+    run_request(
+        &state,
+        &request_context,
+        "/query/explain",
+        timeouts.explain,
+        async {
+            C::query_explain(
+                state.configuration(),
+                state.state().await?,
+                &request_context,
+                request,
+            )
+            .await
+        },
+    )
+    .await
 }
 
 async fn post_mutation_explain<C: Connector>(
     State(state): State<ServerState<C>>,
+    Extension(timeouts): Extension<RequestTimeouts>,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<MutationRequest>, JsonRejection>,
 ) -> Result<JsonResponse<ExplainResponse>> {
-    C::mutation_explain(state.configuration(), state.state().await?, request).await
+    check_mutation_explain_capabilities(
+        &state.parsed_capabilities().await?.capabilities,
+        &request,
+    )?;
+    let request_context = make_request_context(&state, headers, timeouts.explain);
+    run_request(
+        &state,
+        &request_context,
+        "/mutation/explain",
+        timeouts.explain,
+        async {
+            C::mutation_explain(
+                state.configuration(),
+                state.state().await?,
+                &request_context,
+                request,
+            )
+            .await
+        },
+    )
+    .await
 }
 
 async fn post_mutation<C: Connector>(
     State(state): State<ServerState<C>>,
+    Extension(timeouts): Extension<RequestTimeouts>,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<MutationRequest>, JsonRejection>,
 ) -> Result<JsonResponse<MutationResponse>> {
-    C::mutation(state.configuration(), state.state().await?, request).await
+    check_mutation_capabilities(&state.parsed_capabilities().await?.capabilities, &request)?;
+    let request_context = make_request_context(&state, headers, timeouts.mutation);
+    run_request(
+        &state,
+        &request_context,
+        "/mutation",
+        timeouts.mutation,
+        async {
+            C::mutation(
+                state.configuration(),
+                state.state().await?,
+                &request_context,
+                request,
+            )
+            .await
+        },
+    )
+    .await
 }
 
 async fn post_query<C: Connector>(
     State(state): State<ServerState<C>>,
+    Extension(timeouts): Extension<RequestTimeouts>,
+    Extension(response_validation): Extension<Option<ResponseValidation>>,
+    Extension(query_cache): Extension<Option<Arc<QueryCache>>>,
+    Extension(query_coalescer): Extension<Option<Arc<QueryCoalescer>>>,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, JsonRejection>,
 ) -> Result<JsonResponse<QueryResponse>> {
-    C::query(state.configuration(), state.state().await?, request).await
+    check_query_capabilities(&state.parsed_capabilities().await?.capabilities, &request)?;
+    let cache_entry = match &query_cache {
+        Some(query_cache) => cache_entry::<C>(&state, query_cache, &request)?,
+        None => None,
+    };
+    if let Some((query_cache, key, _)) = &cache_entry {
+        if let Some(response) = query_cache.get(key) {
+            return Ok(JsonResponse::Serialized(response));
+        }
+    }
+    // keep a copy of the request to validate the response against
+    let validated_request = response_validation.map(|mode| (mode, request.clone()));
+    // identical queries are those which would share a cache entry
+    let coalescing_key = match (&query_coalescer, &cache_entry) {
+        (None, _) => None,
+        (Some(_), Some((_, key, _))) => Some(key.clone()),
+        (Some(_), None) => {
+            Some(CacheKey::new(state.generation(), &request).map_err(ErrorResponse::from_error)?)
+        }
+    };
+    let request_context = make_request_context(&state, headers, timeouts.query);
+    // the deadline applies to waiting for an identical query, as well as to running the query
+    let response = run_request(&state, &request_context, "/query", timeouts.query, async {
+        let query = async {
+            C::query(
+                state.configuration(),
+                state.state().await?,
+                &request_context,
+                request,
+            )
+            .await
+        };
+        match query_coalescer.as_deref().zip(coalescing_key) {
+            Some((query_coalescer, key)) => query_coalescer.run(key, &request_context, query).await,
+            None => query.await,
+        }
+    })
+    .await?;
+    let response = match validated_request {
+        Some((mode, request)) => validate_response(&state, mode, &request, response).await?,
+        None => response,
+    };
+    match cache_entry {
+        Some((query_cache, key, ttl)) => cache_response(query_cache, key, ttl, response).await,
+        None => Ok(response),
+    }
+}
+
+/// Where to cache the response to a query, and for how long, if the connector allows the
+/// collections it involves to be cached.
+fn cache_entry<'a, C: Connector>(
+    state: &ServerState<C>,
+    query_cache: &'a QueryCache,
+    request: &QueryRequest,
+) -> Result<Option<(&'a QueryCache, CacheKey, Duration)>> {
+    let Some(ttl) = query_cache_ttl::<C>(state.configuration(), request) else {
+        return Ok(None);
+    };
+    let key = CacheKey::new(state.generation(), request).map_err(ErrorResponse::from_error)?;
+    Ok(Some((query_cache, key, ttl)))
+}
+
+/// Cache the serialized response. Streamed responses are not cached, as they are expected to be
+/// too large to hold in memory.
+async fn cache_response(
+    query_cache: &QueryCache,
+    key: CacheKey,
+    ttl: Duration,
+    response: JsonResponse<QueryResponse>,
+) -> Result<JsonResponse<QueryResponse>> {
+    if let JsonResponse::Streamed(_) = response {
+        return Ok(response);
+    }
+    let response = response.into_bytes::<ErrorResponse>().await?;
+    query_cache.insert(key, response.clone(), ttl);
+    Ok(JsonResponse::Serialized(response))
+}
+
+async fn validate_response<C: Connector>(
+    state: &ServerState<C>,
+    mode: ResponseValidation,
+    request: &QueryRequest,
+    response: JsonResponse<QueryResponse>,
+) -> Result<JsonResponse<QueryResponse>> {
+    let response = response.into_value::<ErrorResponse>().await?;
+    let schema = state.parsed_schema().await?;
+    if let Err(err) = validate_query_response(schema, request, &response) {
+        tracing::error!(
+            meta.signal_type = "log",
+            event.domain = "ndc",
+            event.name = "Invalid query response",
+            name = "Invalid query response",
+            body = %err,
+            error = true,
+        );
+        if mode == ResponseValidation::Fail {
+            return Err(err);
+        }
+    }
+    Ok(JsonResponse::Value(response))
 }
 
 #[cfg(feature = "ndc-test")]
@@ -459,9 +1448,9 @@ mod ndc_test_commands {
     use std::path::PathBuf;
     use std::process::exit;
 
-    use crate::json_response::JsonResponse;
+    use crate::request_context::RequestContext;
 
-    use super::{BenchCommand, Connector, ConnectorSetup};
+    use super::{BenchCommand, Connector, ConnectorSetup, ErrorResponse};
 
     struct ConnectorAdapter<C: Connector> {
         configuration: C::Configuration,
@@ -473,34 +1462,48 @@ mod ndc_test_commands {
         async fn get_capabilities(
             &self,
         ) -> Result<ndc_models::CapabilitiesResponse, ndc_test::error::Error> {
-            super::get_capabilities::<C>()
+            ndc_sdk_core::schema::get_capabilities::<C>()
                 .await
                 .into_value::<Box<dyn std::error::Error + Send + Sync>>()
+                .await
                 .map_err(ndc_test::error::Error::OtherError)
         }
 
         async fn get_schema(&self) -> Result<ndc_models::SchemaResponse, ndc_test::error::Error> {
             Ok(C::get_schema(&self.configuration)
-                .await
-                .and_then(JsonResponse::into_value)?)
+                .await?
+                .into_value::<ErrorResponse>()
+                .await?)
         }
 
         async fn query(
             &self,
             request: ndc_models::QueryRequest,
         ) -> Result<ndc_models::QueryResponse, ndc_test::error::Error> {
-            Ok(C::query(&self.configuration, &self.state, request)
-                .await
-                .and_then(JsonResponse::into_value)?)
+            Ok(C::query(
+                &self.configuration,
+                &self.state,
+                &RequestContext::default(),
+                request,
+            )
+            .await?
+            .into_value::<ErrorResponse>()
+            .await?)
         }
 
         async fn mutation(
             &self,
             request: ndc_models::MutationRequest,
         ) -> Result<ndc_models::MutationResponse, ndc_test::error::Error> {
-            Ok(C::mutation(&self.configuration, &self.state, request)
-                .await
-                .and_then(JsonResponse::into_value)?)
+            Ok(C::mutation(
+                &self.configuration,
+                &self.state,
+                &RequestContext::default(),
+                request,
+            )
+            .await?
+            .into_value::<ErrorResponse>()
+            .await?)
         }
     }
 
@@ -617,3 +1620,311 @@ async fn check_health(CheckHealthCommand { host, port }: CheckHealthCommand) ->
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io::Write as _;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures_util::{stream, StreamExt as _};
+    use http::{header, StatusCode};
+    use serde_json::json;
+    use tokio::sync::Notify;
+
+    use super::{create_router, RequestTimeouts, ResponseCompression, RouterOptions};
+    use crate::connector::example::Example;
+    use crate::json_response::JsonResponse;
+    use crate::request_context::RequestContext;
+    use crate::state::init_server_state;
+    use crate::test_support::TestConnector;
+    use ndc_sdk_core::test_client::TestClient;
+
+    #[tokio::test]
+    async fn rejects_requests_using_unsupported_capabilities() -> anyhow::Result<()> {
+        let state = init_server_state(Example {}, Path::new(".")).await?;
+        let client = TestClient::new(create_router(state, RouterOptions::default()))?;
+        let query = json!({
+            "collection": "articles",
+            "query": {},
+            "arguments": {},
+            "collection_relationships": {},
+        });
+        let mut query_with_variables = query.clone();
+        query_with_variables["variables"] = json!([{}]);
+        let mutation = json!({ "operations": [], "collection_relationships": {} });
+
+        for (endpoint, request, capability) in [
+            ("/query", &query_with_variables, "query.variables"),
+            ("/query/explain", &query, "query.explain"),
+            ("/mutation/explain", &mutation, "mutation.explain"),
+        ] {
+            let response = client
+                .post(endpoint)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(request.to_string())
+                .send()
+                .await?;
+            assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED, "{endpoint}");
+            let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+            assert_eq!(body["details"]["capability"], capability, "{endpoint}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compresses_responses_above_the_minimum_size() -> anyhow::Result<()> {
+        // the capabilities are a few hundred bytes long
+        for (min_size, encoding) in [(64, Some("gzip")), (1024, None)] {
+            let state = init_server_state(Example {}, Path::new(".")).await?;
+            let client = TestClient::new(create_router(
+                state,
+                RouterOptions {
+                    response_compression: Some(ResponseCompression { min_size }),
+                    ..RouterOptions::default()
+                },
+            ))?;
+
+            let response = client
+                .get("/capabilities")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .send()
+                .await?;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|encoding| encoding.to_str())
+                    .transpose()?,
+                encoding,
+                "minimum size {min_size}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decompresses_request_bodies() -> anyhow::Result<()> {
+        let state = init_server_state(Example {}, Path::new(".")).await?;
+        let client = TestClient::new(create_router(state, RouterOptions::default()))?;
+        let query = json!({
+            "collection": "articles",
+            "query": {},
+            "arguments": {},
+            "collection_relationships": {},
+            "variables": [{}],
+        });
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(query.to_string().as_bytes())?;
+
+        let response = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(encoder.finish()?)
+            .send()
+            .await?;
+
+        // the body can only be checked against the capabilities once it is decompressed
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        assert_eq!(body["details"]["capability"], "query.variables");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_request_bodies_with_unsupported_encodings() -> anyhow::Result<()> {
+        let state = init_server_state(Example {}, Path::new(".")).await?;
+        let client = TestClient::new(create_router(state, RouterOptions::default()))?;
+
+        let response = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "compress")
+            .body("{}")
+            .send()
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.headers()[header::ACCEPT_ENCODING], "zstd,gzip");
+        let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        assert_eq!(body["message"], "Unsupported request body encoding");
+        assert_eq!(
+            body["details"]["supportedEncodings"],
+            json!(["zstd", "gzip"])
+        );
+        Ok(())
+    }
+
+    /// What happened to the queries sent to a [`waiting`] connector.
+    #[derive(Default)]
+    struct Probe {
+        started: Notify,
+        cancelled: Notify,
+    }
+
+    /// Report to the probe that a query has started, and when it is cancelled.
+    fn report(probe: &Arc<Probe>, request_context: &RequestContext) {
+        // watch for cancellation as work spawned by the connector would, outliving the query
+        let cancellation_token = request_context.cancellation_token().clone();
+        let watcher = probe.clone();
+        tokio::spawn(async move {
+            cancellation_token.cancelled().await;
+            watcher.cancelled.notify_one();
+        });
+        probe.started.notify_one();
+    }
+
+    /// A connector whose queries wait forever, reporting to the probe.
+    fn waiting(probe: &Arc<Probe>) -> TestConnector {
+        let probe = probe.clone();
+        TestConnector {
+            query: Some(Arc::new(move |request_context| {
+                report(&probe, request_context);
+                Box::pin(std::future::pending())
+            })),
+            ..TestConnector::default()
+        }
+    }
+
+    /// A connector whose queries stream the start of a response and then wait forever, reporting
+    /// to the probe.
+    fn streaming(probe: &Arc<Probe>) -> TestConnector {
+        let probe = probe.clone();
+        TestConnector {
+            query: Some(Arc::new(move |request_context| {
+                report(&probe, request_context);
+                let start = stream::once(async { Ok::<_, Infallible>(Bytes::from_static(b"[")) });
+                Box::pin(
+                    async move { Ok(JsonResponse::from_stream(start.chain(stream::pending()))) },
+                )
+            })),
+            ..TestConnector::default()
+        }
+    }
+
+    fn query() -> String {
+        json!({
+            "collection": "articles",
+            "query": {},
+            "arguments": {},
+            "collection_relationships": {},
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn cancels_the_request_when_the_client_disconnects() -> anyhow::Result<()> {
+        let probe = Arc::new(Probe::default());
+        let state = init_server_state(waiting(&probe), Path::new(".")).await?;
+        let client = TestClient::new(create_router(state, RouterOptions::default()))?;
+
+        let request = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(query())
+            .send();
+        let request = tokio::spawn(request);
+        tokio::time::timeout(Duration::from_secs(5), probe.started.notified()).await?;
+        // dropping the request closes the connection
+        request.abort();
+
+        tokio::time::timeout(Duration::from_secs(5), probe.cancelled.notified()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancels_the_request_when_the_client_disconnects_during_a_streamed_response(
+    ) -> anyhow::Result<()> {
+        let probe = Arc::new(Probe::default());
+        let state = init_server_state(streaming(&probe), Path::new(".")).await?;
+        let client = TestClient::new(create_router(state, RouterOptions::default()))?;
+
+        let mut response = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(query())
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        // the handler has returned, and the response is being sent
+        assert_eq!(response.chunk().await?.as_deref(), Some(&b"["[..]));
+        // dropping the unfinished response closes the connection
+        drop(response);
+
+        tokio::time::timeout(Duration::from_secs(5), probe.cancelled.notified()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_requests_which_exceed_their_deadline() -> anyhow::Result<()> {
+        let probe = Arc::new(Probe::default());
+        let state = init_server_state(waiting(&probe), Path::new(".")).await?;
+        let client = TestClient::new(create_router(
+            state,
+            RouterOptions {
+                request_timeouts: RequestTimeouts {
+                    query: Some(Duration::from_millis(50)),
+                    ..RequestTimeouts::default()
+                },
+                ..RouterOptions::default()
+            },
+        ))?;
+
+        let response = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(query())
+            .send()
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        assert_eq!(body["message"], "Request deadline exceeded");
+        assert_eq!(
+            body["details"],
+            json!({ "endpoint": "/query", "timeoutMillis": 50 })
+        );
+        // the connector is told to stop working on the query
+        tokio::time::timeout(Duration::from_secs(5), probe.cancelled.notified()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ends_streamed_responses_which_exceed_their_deadline() -> anyhow::Result<()> {
+        let probe = Arc::new(Probe::default());
+        let state = init_server_state(streaming(&probe), Path::new(".")).await?;
+        let client = TestClient::new(create_router(
+            state,
+            RouterOptions {
+                request_timeouts: RequestTimeouts {
+                    query: Some(Duration::from_millis(50)),
+                    ..RequestTimeouts::default()
+                },
+                ..RouterOptions::default()
+            },
+        ))?;
+
+        let mut response = client
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(query())
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.chunk().await?.as_deref(), Some(&b"["[..]));
+
+        // the response is aborted rather than ended, so the client sees that it is incomplete
+        let rest = tokio::time::timeout(Duration::from_secs(5), response.chunk()).await?;
+        assert!(rest.is_err(), "{rest:?}");
+        tokio::time::timeout(Duration::from_secs(5), probe.cancelled.notified()).await?;
+        Ok(())
+    }
+}
@@ -1,20 +1,476 @@
-use prometheus::{Registry, TextEncoder};
+use std::fmt::Write as _;
+use std::time::{Duration, SystemTime};
+
+use http::HeaderValue;
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, ProtobufEncoder, Registry, TextEncoder};
 
 use crate::connector::error::{ErrorResponse, Result};
 use crate::connector::Connector;
 
-pub fn fetch_metrics<C: Connector>(
+/// The formats in which metrics can be served, as negotiated through the `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsFormat {
+    /// The Prometheus text format, which is served by default.
+    Text,
+    /// The OpenMetrics text format, which also carries exemplars.
+    OpenMetrics,
+    /// The Prometheus protobuf format, as length-delimited `MetricFamily` messages.
+    Protobuf,
+}
+
+impl MetricsFormat {
+    /// Choose the format the client prefers, from the value of its `Accept` header.
+    ///
+    /// Formats are ranked by their quality values. Clients which do not send the header, or accept
+    /// none of the formats, are served the Prometheus text format.
+    pub fn negotiate(accept: Option<&HeaderValue>) -> Self {
+        let Some(accept) = accept.and_then(|accept| accept.to_str().ok()) else {
+            return Self::Text;
+        };
+        let mut best = (Self::Text, 0.0);
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut proto = None;
+            for parameter in parts {
+                match parameter.split_once('=') {
+                    Some(("q", value)) => quality = value.parse().unwrap_or(0.0),
+                    Some(("proto", value)) => proto = Some(value),
+                    _ => {}
+                }
+            }
+            let format = match media_type.as_str() {
+                "application/openmetrics-text" => Self::OpenMetrics,
+                "application/vnd.google.protobuf"
+                    if matches!(proto, None | Some("io.prometheus.client.MetricFamily")) =>
+                {
+                    Self::Protobuf
+                }
+                "text/plain" | "text/*" | "*/*" => Self::Text,
+                _ => continue,
+            };
+            // the first of equally-preferred formats wins
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+
+    /// The value of the `Content-Type` header for metrics in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => prometheus::TEXT_FORMAT,
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Self::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+}
+
+/// An example of an observation in a histogram bucket, such as a request which took that long,
+/// linking the bucket to the trace of that request.
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    pub timestamp: SystemTime,
+}
+
+/// A source of exemplars for histogram buckets.
+///
+/// Exemplars are only served in the OpenMetrics format.
+pub trait Exemplars: Sync {
+    /// The exemplar for the bucket of a histogram with the given upper bound, if there is one.
+    fn exemplar(&self, family: &str, metric: &Metric, upper_bound: f64) -> Option<Exemplar>;
+}
+
+impl Exemplars for () {
+    fn exemplar(&self, _family: &str, _metric: &Metric, _upper_bound: f64) -> Option<Exemplar> {
+        None
+    }
+}
+
+/// Update the connector's metrics and encode every metric in the registry in the given format.
+///
+/// If the connector takes longer than `timeout` to update its metrics, the update is cancelled,
+/// and the metrics are encoded with their previous values.
+pub async fn fetch_metrics<C: Connector>(
     configuration: &C::Configuration,
     state: &C::State,
     metrics: &Registry,
-) -> Result<String> {
-    let encoder = TextEncoder::new();
-
-    C::fetch_metrics(configuration, state)?;
+    timeout: Duration,
+    format: MetricsFormat,
+    exemplars: &dyn Exemplars,
+) -> Result<Vec<u8>> {
+    match tokio::time::timeout(timeout, C::fetch_metrics_async(configuration, state)).await {
+        Ok(result) => result?,
+        Err(_elapsed) => tracing::warn!(
+            meta.signal_type = "log",
+            event.domain = "ndc",
+            event.name = "Fetching metrics timed out",
+            name = "Fetching metrics timed out",
+            body = format!("The connector did not update its metrics within {timeout:?}"),
+        ),
+    }
 
     let metric_families = &metrics.gather();
 
-    encoder
-        .encode_to_string(metric_families)
-        .map_err(ErrorResponse::from_error)
+    let mut buffer = vec![];
+    match format {
+        MetricsFormat::Text => TextEncoder::new()
+            .encode(metric_families, &mut buffer)
+            .map_err(ErrorResponse::from_error)?,
+        MetricsFormat::OpenMetrics => {
+            buffer = encode_open_metrics(metric_families, exemplars).into_bytes();
+        }
+        MetricsFormat::Protobuf => ProtobufEncoder::new()
+            .encode(metric_families, &mut buffer)
+            .map_err(ErrorResponse::from_error)?,
+    }
+    Ok(buffer)
+}
+
+/// Encode metrics in the OpenMetrics text format.
+///
+/// In OpenMetrics, the name of a counter does not include the `_total` suffix, which is added to
+/// its samples instead, so this is removed from the names of counters which have it.
+///
+/// The optional `_created` samples are not written, as the `prometheus` crate does not record when
+/// each metric was created.
+fn encode_open_metrics(metric_families: &[MetricFamily], exemplars: &dyn Exemplars) -> String {
+    let mut output = String::new();
+    for family in metric_families {
+        let name = family.get_name();
+        let (family_name, metric_type) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(output, "# TYPE {family_name} {metric_type}");
+        if !family.get_help().is_empty() {
+            let _ = writeln!(output, "# HELP {family_name} {}", escape(family.get_help()));
+        }
+        for metric in family.get_metric() {
+            if family.get_field_type() == MetricType::HISTOGRAM {
+                write_histogram(&mut output, family_name, metric, exemplars);
+                continue;
+            }
+            let mut sample = |suffix: &str, label: Option<(&str, f64)>, value: String| {
+                write_sample(
+                    &mut output,
+                    family_name,
+                    suffix,
+                    metric.get_label(),
+                    label,
+                    &value,
+                );
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    sample("_total", None, number(metric.get_counter().get_value()));
+                }
+                MetricType::GAUGE => sample("", None, number(metric.get_gauge().get_value())),
+                MetricType::UNTYPED => sample("", None, number(metric.get_untyped().get_value())),
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        sample(
+                            "",
+                            Some(("quantile", quantile.get_quantile())),
+                            number(quantile.get_value()),
+                        );
+                    }
+                    sample("_sum", None, number(summary.get_sample_sum()));
+                    sample("_count", None, summary.get_sample_count().to_string());
+                }
+                MetricType::HISTOGRAM => unreachable!("histograms are written above"),
+            }
+        }
+    }
+    output.push_str("# EOF\n");
+    output
+}
+
+fn write_histogram(output: &mut String, name: &str, metric: &Metric, exemplars: &dyn Exemplars) {
+    let histogram = metric.get_histogram();
+    let labels = metric.get_label();
+    let mut buckets = histogram
+        .get_bucket()
+        .iter()
+        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+        .collect::<Vec<_>>();
+    // OpenMetrics requires the +Inf bucket, which may be left implicit
+    if buckets.last().map(|(upper_bound, _)| *upper_bound) != Some(f64::INFINITY) {
+        buckets.push((f64::INFINITY, histogram.get_sample_count()));
+    }
+    for (upper_bound, count) in buckets {
+        write_sample(
+            output,
+            name,
+            "_bucket",
+            labels,
+            Some(("le", upper_bound)),
+            &count.to_string(),
+        );
+        if let Some(exemplar) = exemplars.exemplar(name, metric, upper_bound) {
+            // replace the newline with the exemplar, which follows the sample on the same line
+            output.pop();
+            let timestamp = exemplar
+                .timestamp
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let _ = writeln!(
+                output,
+                " # {{trace_id=\"{}\"}} {} {timestamp:.3}",
+                escape(&exemplar.trace_id),
+                number(exemplar.value),
+            );
+        }
+    }
+    write_sample(
+        output,
+        name,
+        "_sum",
+        labels,
+        None,
+        &number(histogram.get_sample_sum()),
+    );
+    write_sample(
+        output,
+        name,
+        "_count",
+        labels,
+        None,
+        &histogram.get_sample_count().to_string(),
+    );
+}
+
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, f64)>,
+    value: &str,
+) {
+    output.push_str(name);
+    output.push_str(suffix);
+    let extra_label = extra_label.map(|(name, value)| (name, label_number(value)));
+    let mut labels = labels
+        .iter()
+        .map(|label| (label.get_name(), escape(label.get_value())))
+        .chain(extra_label)
+        .peekable();
+    if labels.peek().is_some() {
+        output.push('{');
+        for (index, (name, value)) in labels.enumerate() {
+            if index > 0 {
+                output.push(',');
+            }
+            let _ = write!(output, "{name}=\"{value}\"");
+        }
+        output.push('}');
+    }
+    let _ = writeln!(output, " {value}");
+}
+
+fn number(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+/// A number as the value of an `le` or `quantile` label, which must be in its canonical form, as a
+/// float, so that `1` is written as `1.0`.
+fn label_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{value:?}")
+    } else {
+        number(value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use http::HeaderValue;
+    use prometheus::proto::Metric;
+    use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge, Registry};
+    use prometheus_parse::{HistogramCount, Scrape, Value};
+
+    use super::{encode_open_metrics, fetch_metrics, Exemplar, Exemplars, MetricsFormat};
+    use crate::test_support::TestConnector;
+
+    #[test]
+    fn negotiates_the_preferred_format() {
+        let negotiate =
+            |accept: &str| MetricsFormat::negotiate(Some(&HeaderValue::from_str(accept).unwrap()));
+
+        assert_eq!(MetricsFormat::negotiate(None), MetricsFormat::Text);
+        assert_eq!(
+            negotiate("application/openmetrics-text; version=1.0.0"),
+            MetricsFormat::OpenMetrics
+        );
+        assert_eq!(
+            negotiate(
+                "application/openmetrics-text;version=1.0.0;q=0.5,text/plain;version=0.0.4;q=0.8"
+            ),
+            MetricsFormat::Text
+        );
+        assert_eq!(
+            negotiate(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;q=0.3"
+            ),
+            MetricsFormat::Protobuf
+        );
+        // only the protobuf encoding of metric families is supported
+        assert_eq!(
+            negotiate("application/vnd.google.protobuf;proto=example.Other,text/plain;q=0.1"),
+            MetricsFormat::Text
+        );
+        // the first of equally-preferred formats wins
+        assert_eq!(
+            negotiate("application/openmetrics-text,application/vnd.google.protobuf"),
+            MetricsFormat::OpenMetrics
+        );
+        assert_eq!(negotiate("application/json"), MetricsFormat::Text);
+        assert_eq!(
+            negotiate("application/openmetrics-text;q=0"),
+            MetricsFormat::Text
+        );
+    }
+
+    struct TestExemplars;
+
+    impl Exemplars for TestExemplars {
+        // the bounds are the same values, so can be compared exactly
+        #[allow(clippy::float_cmp)]
+        fn exemplar(&self, _family: &str, _metric: &Metric, upper_bound: f64) -> Option<Exemplar> {
+            (upper_bound == 1.0).then(|| Exemplar {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+                value: 0.75,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+            })
+        }
+    }
+
+    #[test]
+    fn encodes_open_metrics() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        let requests = IntCounter::new("requests_total", "Number of requests")?;
+        registry.register(Box::new(requests.clone()))?;
+        requests.inc_by(2);
+        let durations = Histogram::with_opts(
+            HistogramOpts::new("durations", "Request durations").buckets(vec![0.5, 1.0, 2.5]),
+        )?;
+        registry.register(Box::new(durations.clone()))?;
+        durations.observe(0.75);
+
+        let output = encode_open_metrics(&registry.gather(), &TestExemplars);
+
+        // the parser reads the Prometheus text format, which OpenMetrics extends with exemplars
+        // after the samples, and the end marker
+        assert!(output.ends_with("\n# EOF\n"), "{output}");
+        let mut exemplars = Vec::new();
+        let lines = output
+            .lines()
+            .map(|line| match line.split_once(" # ") {
+                Some((sample, exemplar)) => {
+                    exemplars.push((sample.to_owned(), exemplar.to_owned()));
+                    Ok(sample.to_owned())
+                }
+                None => Ok(line.to_owned()),
+            })
+            .collect::<Vec<_>>();
+        let scrape = Scrape::parse(lines.into_iter())?;
+
+        let value = |name: &str| {
+            scrape
+                .samples
+                .iter()
+                .find(|sample| sample.metric == name)
+                .map(|sample| sample.value.clone())
+        };
+        // counters are typed by their family name, without the suffix of their samples
+        assert!(output.contains("# TYPE requests counter\n"), "{output}");
+        assert_eq!(scrape.docs["requests"], "Number of requests");
+        assert_eq!(value("requests_total"), Some(Value::Untyped(2.0)));
+        assert_eq!(scrape.docs["durations"], "Request durations");
+        assert_eq!(
+            value("durations"),
+            Some(Value::Histogram(
+                [(0.5, 0.0), (1.0, 1.0), (2.5, 1.0), (f64::INFINITY, 1.0)]
+                    .into_iter()
+                    .map(|(less_than, count)| HistogramCount { less_than, count })
+                    .collect()
+            ))
+        );
+        assert_eq!(value("durations_sum"), Some(Value::Untyped(0.75)));
+        assert_eq!(value("durations_count"), Some(Value::Untyped(1.0)));
+        assert_eq!(
+            exemplars,
+            [(
+                r#"durations_bucket{le="1.0"} 1"#.to_owned(),
+                r#"{trace_id="4bf92f3577b34da6a3ce929d0e0e4736"} 0.75 1700000000.500"#.to_owned()
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_the_previous_metrics_if_updating_them_times_out() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        let connections = IntGauge::new("connections", "Number of open connections")?;
+        registry.register(Box::new(connections.clone()))?;
+        connections.set(1);
+        // the metrics take an hour to update
+        let connector = TestConnector {
+            fetch_metrics: Some(Arc::new(move || {
+                let connections = connections.clone();
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+                    connections.set(2);
+                    Ok(())
+                })
+            })),
+            ..TestConnector::default()
+        };
+
+        let start = Instant::now();
+        let output = fetch_metrics::<TestConnector>(
+            &(),
+            &connector,
+            &registry,
+            Duration::from_millis(50),
+            MetricsFormat::Text,
+            &(),
+        )
+        .await?;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let output = String::from_utf8(output)?;
+        assert!(
+            output.lines().any(|line| line == "connections 1"),
+            "{output}"
+        );
+        Ok(())
+    }
 }
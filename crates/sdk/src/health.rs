@@ -0,0 +1,182 @@
+//! The bodies of the `/health/live`, `/health/ready` and `/health/connected` endpoints.
+
+use std::future::Future;
+
+use axum::response::IntoResponse;
+use axum::Json;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::connector::Result;
+
+/// The outcome of a set of health checks.
+///
+/// This is returned with a 200 status code if every check passed, and a 503 status code
+/// otherwise.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<HealthCheck>,
+}
+
+/// The outcome of a single health check.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    /// The check passed.
+    Ok,
+    /// The check failed.
+    Failed,
+    /// The check was not run, because a check it depends on failed.
+    Skipped,
+}
+
+impl HealthReport {
+    /// A report with no checks, which is healthy.
+    pub fn new() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            checks: vec![],
+        }
+    }
+
+    /// Run a check and record its outcome.
+    ///
+    /// If an earlier check failed, the check is not run, and is recorded as skipped. The value
+    /// produced by the check is returned if it passed.
+    pub async fn check<T, F>(&mut self, name: &str, check: F) -> Option<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let (status, message, value) = if self.status == HealthStatus::Ok {
+            match check.await {
+                Ok(value) => (HealthStatus::Ok, None, Some(value)),
+                Err(err) => (HealthStatus::Failed, Some(err.to_string()), None),
+            }
+        } else {
+            (HealthStatus::Skipped, None, None)
+        };
+        if status == HealthStatus::Failed {
+            self.status = HealthStatus::Failed;
+        }
+        self.checks.push(HealthCheck {
+            name: name.to_owned(),
+            status,
+            message,
+        });
+        value
+    }
+}
+
+impl Default for HealthReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntoResponse for HealthReport {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self.status {
+            HealthStatus::Ok => StatusCode::OK,
+            HealthStatus::Failed | HealthStatus::Skipped => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody as _;
+    use axum::response::IntoResponse as _;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use super::{HealthReport, HealthStatus};
+    use crate::connector::ErrorResponse;
+
+    #[tokio::test]
+    async fn reports_healthy_when_every_check_passes() -> anyhow::Result<()> {
+        let mut report = HealthReport::new();
+
+        assert_eq!(report.check("state", async { Ok(1) }).await, Some(1));
+        assert_eq!(report.check("readiness", async { Ok(()) }).await, Some(()));
+        assert_eq!(report.status, HealthStatus::Ok);
+
+        let response = report.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().data().await.expect("no body")?;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body)?,
+            json!({
+                "status": "ok",
+                "checks": [
+                    { "name": "state", "status": "ok" },
+                    { "name": "readiness", "status": "ok" },
+                ],
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_the_checks_after_a_failure() -> anyhow::Result<()> {
+        let mut report = HealthReport::new();
+        let mut connected = false;
+
+        assert_eq!(report.check("state", async { Ok(()) }).await, Some(()));
+        let readiness = report
+            .check::<(), _>("readiness", async {
+                Err(ErrorResponse::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Database unavailable".to_owned(),
+                    json!({}),
+                ))
+            })
+            .await;
+        assert_eq!(readiness, None);
+        let connectivity = report
+            .check("connectivity", async {
+                connected = true;
+                Ok(())
+            })
+            .await;
+        assert_eq!(connectivity, None);
+        assert!(!connected);
+        assert_eq!(report.status, HealthStatus::Failed);
+        assert_eq!(
+            report
+                .checks
+                .iter()
+                .map(|check| check.status)
+                .collect::<Vec<_>>(),
+            [
+                HealthStatus::Ok,
+                HealthStatus::Failed,
+                HealthStatus::Skipped
+            ]
+        );
+
+        let response = report.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().data().await.expect("no body")?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["checks"][1]["status"], "failed");
+        assert!(body["checks"][1]["message"]
+            .as_str()
+            .is_some_and(|message| message.contains("Database unavailable")));
+        assert_eq!(
+            body["checks"][2],
+            json!({ "name": "connectivity", "status": "skipped" })
+        );
+        Ok(())
+    }
+}
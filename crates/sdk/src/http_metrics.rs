@@ -0,0 +1,306 @@
+//! Metrics describing the HTTP requests served by the SDK.
+//!
+//! These are recorded for every connector, so that each can be monitored with the same
+//! dashboards. Requests are labelled by the route they matched, such as `/query`, rather than by
+//! their path, so that the number of series stays bounded.
+//!
+//! The request duration histogram keeps the most recent sampled trace in each bucket as an
+//! exemplar, which is served to scrapers asking for the OpenMetrics format.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{MatchedPath, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::Request;
+use opentelemetry::trace::TraceContextExt;
+use prometheus::core::Collector;
+use prometheus::proto::Metric;
+use prometheus::{
+    exponential_buckets, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, DEFAULT_BUCKETS,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::fetch_metrics::{Exemplar, Exemplars};
+use crate::metrics::{self, Metrics};
+
+const DURATION_METRIC: &str = "ndc_sdk_http_request_duration_seconds";
+
+pub(crate) struct HttpMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    // the exemplars of each endpoint, indexed by bucket, with the +Inf bucket last
+    duration_exemplars: Mutex<HashMap<String, Vec<Option<Exemplar>>>>,
+    in_flight: IntGaugeVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
+}
+
+impl HttpMetrics {
+    pub(crate) fn new() -> Self {
+        // from 64 bytes to 16 megabytes
+        let size_buckets = exponential_buckets(64.0, 4.0, 10).unwrap(); // cannot fail, as the parameters are valid
+        Self {
+            requests: metrics::counter_vec(
+                "ndc_sdk_http_requests_total",
+                "Number of HTTP requests served, by endpoint and status code",
+                &["endpoint", "status"],
+            ),
+            duration: metrics::histogram_vec(
+                DURATION_METRIC,
+                "Time taken to produce the response to an HTTP request, by endpoint",
+                DEFAULT_BUCKETS.to_vec(),
+                &["endpoint"],
+            ),
+            duration_exemplars: Mutex::new(HashMap::new()),
+            in_flight: metrics::gauge_vec(
+                "ndc_sdk_http_requests_in_flight",
+                "Number of HTTP requests being served, by endpoint",
+                &["endpoint"],
+            ),
+            request_size: metrics::histogram_vec(
+                "ndc_sdk_http_request_size_bytes",
+                "Size of HTTP request bodies as sent by the client, by endpoint",
+                size_buckets.clone(),
+                &["endpoint"],
+            ),
+            response_size: metrics::histogram_vec(
+                "ndc_sdk_http_response_size_bytes",
+                "Size of HTTP response bodies before compression, by endpoint",
+                size_buckets,
+                &["endpoint"],
+            ),
+        }
+    }
+
+    /// Record the request duration, keeping the current trace, if it is sampled, as the exemplar
+    /// for its bucket.
+    fn observe_duration(&self, endpoint: &str, seconds: f64) {
+        self.duration
+            .with_label_values(&[endpoint])
+            .observe(seconds);
+        let span_context = tracing::Span::current()
+            .context()
+            .span()
+            .span_context()
+            .clone();
+        if !span_context.is_sampled() {
+            return;
+        }
+        let bucket = DEFAULT_BUCKETS
+            .iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+            .unwrap_or(DEFAULT_BUCKETS.len());
+        let mut exemplars = self
+            .duration_exemplars
+            .lock()
+            .expect("exemplar lock poisoned");
+        let exemplars = exemplars
+            .entry(endpoint.to_owned())
+            .or_insert_with(|| vec![None; DEFAULT_BUCKETS.len() + 1]);
+        exemplars[bucket] = Some(Exemplar {
+            trace_id: span_context.trace_id().to_string(),
+            value: seconds,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
+
+impl Metrics for HttpMetrics {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.requests.clone()),
+            Box::new(self.duration.clone()),
+            Box::new(self.in_flight.clone()),
+            Box::new(self.request_size.clone()),
+            Box::new(self.response_size.clone()),
+        ]
+    }
+}
+
+impl Exemplars for HttpMetrics {
+    fn exemplar(&self, family: &str, metric: &Metric, upper_bound: f64) -> Option<Exemplar> {
+        if family != DURATION_METRIC {
+            return None;
+        }
+        let endpoint = metric
+            .get_label()
+            .iter()
+            .find(|label| label.get_name() == "endpoint")?
+            .get_value();
+        // the bounds are the same values, so can be compared exactly
+        #[allow(clippy::float_cmp)]
+        let bucket = DEFAULT_BUCKETS
+            .iter()
+            .position(|bound| *bound == upper_bound)
+            .unwrap_or(DEFAULT_BUCKETS.len());
+        self.duration_exemplars
+            .lock()
+            .expect("exemplar lock poisoned")
+            .get(endpoint)?[bucket]
+            .clone()
+    }
+}
+
+/// Middleware which records the metrics for each request.
+///
+/// The duration is measured until the response headers are produced. Bodies whose size is not
+/// known up front, such as chunked requests and streamed responses, are not included in the size
+/// histograms.
+pub(crate) async fn record_http_metrics(
+    State(metrics): State<Arc<HttpMetrics>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    let labels = [endpoint.as_str()];
+    if let Some(size) = request.body().size_hint().exact() {
+        #[allow(clippy::cast_precision_loss)]
+        metrics
+            .request_size
+            .with_label_values(&labels)
+            .observe(size as f64);
+    }
+
+    let in_flight = InFlight::new(metrics.in_flight.with_label_values(&labels));
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.observe_duration(&endpoint, start.elapsed().as_secs_f64());
+    drop(in_flight);
+
+    metrics
+        .requests
+        .with_label_values(&[endpoint.as_str(), response.status().as_str()])
+        .inc();
+    if let Some(size) = response.body().size_hint().exact() {
+        #[allow(clippy::cast_precision_loss)]
+        metrics
+            .response_size
+            .with_label_values(&labels)
+            .observe(size as f64);
+    }
+    response
+}
+
+/// A request counted as in flight until it is dropped, which happens early if the client
+/// disconnects.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use axum::extract::Path;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use http::StatusCode;
+    use prometheus::proto::MetricFamily;
+    use prometheus::Registry;
+
+    use super::{record_http_metrics, HttpMetrics};
+    use crate::metrics::Metrics as _;
+    use ndc_sdk_core::test_client::TestClient;
+
+    #[tokio::test]
+    async fn records_requests_by_route_and_status() -> anyhow::Result<()> {
+        let metrics = Arc::new(HttpMetrics::new());
+        let registry = Registry::new();
+        for collector in metrics.collectors() {
+            registry.register(collector)?;
+        }
+        let router = Router::new()
+            .route(
+                "/items/:id",
+                get(|Path(id): Path<u32>| async move {
+                    if id == 0 {
+                        StatusCode::NOT_FOUND
+                    } else {
+                        StatusCode::OK
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(metrics, record_http_metrics));
+        let client = TestClient::new(router)?;
+
+        for path in ["/items/1", "/items/2", "/items/0", "/missing"] {
+            client.get(path).send().await?;
+        }
+
+        let families = registry.gather();
+        let requests = samples(&families, "ndc_sdk_http_requests_total", |metric| {
+            metric.get_counter().get_value()
+        });
+        assert_eq!(
+            requests,
+            BTreeMap::from([
+                ("endpoint=/items/:id,status=200".to_owned(), 2.0),
+                ("endpoint=/items/:id,status=404".to_owned(), 1.0),
+                ("endpoint=unmatched,status=404".to_owned(), 1.0),
+            ])
+        );
+        let durations = samples(
+            &families,
+            "ndc_sdk_http_request_duration_seconds",
+            |metric| {
+                #[allow(clippy::cast_precision_loss)]
+                let count = metric.get_histogram().get_sample_count() as f64;
+                count
+            },
+        );
+        assert_eq!(
+            durations,
+            BTreeMap::from([
+                ("endpoint=/items/:id".to_owned(), 3.0),
+                ("endpoint=unmatched".to_owned(), 1.0),
+            ])
+        );
+        let in_flight = samples(&families, "ndc_sdk_http_requests_in_flight", |metric| {
+            metric.get_gauge().get_value()
+        });
+        assert!(in_flight.values().all(|value| *value == 0.0));
+        Ok(())
+    }
+
+    /// The values of a metric family, by their labels.
+    fn samples(
+        families: &[MetricFamily],
+        name: &str,
+        value: impl Fn(&prometheus::proto::Metric) -> f64,
+    ) -> BTreeMap<String, f64> {
+        families
+            .iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(MetricFamily::get_metric)
+            .map(|metric| {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| format!("{}={}", label.get_name(), label.get_value()))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (labels, value(metric))
+            })
+            .collect()
+    }
+}
@@ -1,10 +1,24 @@
+pub mod admission;
 pub mod check_health;
 pub mod default_main;
 pub mod fetch_metrics;
+pub mod health;
+pub mod http_metrics;
 pub mod json_rejection;
+pub mod rate_limit;
+pub mod reload;
 pub mod tracing;
 
+#[cfg(test)]
+mod test_support;
+
 pub use ndc_models as models;
+pub use ndc_sdk_core::capabilities;
 pub use ndc_sdk_core::connector;
 pub use ndc_sdk_core::json_response;
+pub use ndc_sdk_core::metrics;
+pub use ndc_sdk_core::query_cache;
+pub use ndc_sdk_core::query_coalescing;
+pub use ndc_sdk_core::request_context;
 pub use ndc_sdk_core::state;
+pub use ndc_sdk_core::validation;
@@ -0,0 +1,407 @@
+//! Rate limiting of requests by client.
+//!
+//! Each client has a token bucket, which holds up to `burst` tokens and is refilled at
+//! `requests_per_second`. Each request takes a token, and requests which find the bucket empty are
+//! rejected with a 429 Too Many Requests.
+//!
+//! When a service token secret is set, clients are identified by the token they present, so that
+//! each caller sharing an address, such as tenants behind the engine or a proxy, has a bucket of
+//! its own. Otherwise, clients are identified by their address, which requires the router to be
+//! served with `into_make_service_with_connect_info::<SocketAddr>()`; without it, every client
+//! shares a single bucket.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::{header, HeaderValue, Request, StatusCode};
+use prometheus::core::Collector;
+use prometheus::IntCounterVec;
+use serde_json::json;
+
+use crate::connector::ErrorResponse;
+use crate::metrics::{self, Metrics};
+
+/// The rate at which each client may make requests.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// The sustained number of requests per second.
+    pub requests_per_second: f64,
+    /// The number of requests which may be made at once after a period of inactivity.
+    pub burst: u32,
+}
+
+/// The most clients whose buckets are kept at once. Beyond this, the buckets which were least
+/// recently used are forgotten, so that a flood of clients cannot use unbounded memory.
+const MAX_CLIENTS: usize = 16 * 1024;
+
+/// The token buckets of each client.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    expected_authorization: Option<HeaderValue>,
+    // hashes the tokens clients present, so that they are not kept in memory
+    token_hasher: RandomState,
+    buckets: Mutex<Buckets>,
+    throttled: IntCounterVec,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    // the number of buckets after they were last pruned
+    pruned_len: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// A rate limiter which identifies clients by the token they present if a service token
+    /// secret is set, and by their address otherwise.
+    pub(crate) fn new(limit: RateLimit, service_token_secret: Option<&str>) -> Self {
+        Self {
+            limit,
+            expected_authorization: service_token_secret.and_then(|service_token_secret| {
+                HeaderValue::from_str(&format!("Bearer {service_token_secret}")).ok()
+            }),
+            token_hasher: RandomState::new(),
+            buckets: Mutex::new(Buckets::default()),
+            throttled: metrics::counter_vec(
+                "ndc_sdk_rate_limited_requests_total",
+                "Number of requests rejected because the client exceeded its rate limit, by whether it was authenticated",
+                &["authentication"],
+            ),
+        }
+    }
+
+    /// Identify the client making the request, returning the key of its bucket and whether it is
+    /// authenticated, as a label with a bounded number of values.
+    ///
+    /// Requests are limited before they are authenticated, so invalid tokens are limited in the
+    /// same way as valid ones.
+    fn client(&self, request: &Request<Body>) -> (String, &'static str) {
+        let Some(expected) = &self.expected_authorization else {
+            let address = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map_or_else(
+                    || "unknown".to_owned(),
+                    |ConnectInfo(address)| address.ip().to_string(),
+                );
+            return (address, "anonymous");
+        };
+        let authorization = request.headers().get(header::AUTHORIZATION);
+        let token = authorization.map_or(&[][..], HeaderValue::as_bytes);
+        let key = format!("token {:016x}", self.token_hasher.hash_one(token));
+        if authorization == Some(expected) {
+            (key, "authenticated")
+        } else {
+            (key, "unauthenticated")
+        }
+    }
+
+    /// Take a token from the client's bucket, or return how long until one is available.
+    fn take(&self, client: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(self.limit.burst);
+        let mut buckets = self.buckets.lock().expect("rate limit lock poisoned");
+        let bucket = buckets.buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated_at).as_secs_f64() * self.limit.requests_per_second)
+            .min(capacity);
+        bucket.updated_at = now;
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(
+                Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.limit.requests_per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        };
+        if buckets.buckets.len() > (2 * buckets.pruned_len.max(512)).min(MAX_CLIENTS) {
+            buckets.prune(self.limit, now);
+        }
+        result
+    }
+}
+
+impl Metrics for RateLimiter {
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![Box::new(self.throttled.clone())]
+    }
+}
+
+impl Buckets {
+    /// Forget clients whose buckets have refilled, as they are no different from new ones, and if
+    /// there are still too many, those which were least recently used.
+    fn prune(&mut self, limit: RateLimit, now: Instant) {
+        let capacity = f64::from(limit.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens
+                + now.duration_since(bucket.updated_at).as_secs_f64() * limit.requests_per_second
+                < capacity
+        });
+        if self.buckets.len() > MAX_CLIENTS {
+            let mut updated_at = self
+                .buckets
+                .values()
+                .map(|bucket| bucket.updated_at)
+                .collect::<Vec<_>>();
+            // keep the most recently used half, leaving room for new clients
+            let (_, &mut cutoff, _) = updated_at.select_nth_unstable(MAX_CLIENTS / 2);
+            self.buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+        }
+        self.pruned_len = self.buckets.len();
+    }
+}
+
+/// Middleware which rejects requests from clients which have exceeded their rate limit, if there
+/// is one.
+pub(crate) async fn rate_limit(
+    State(rate_limiter): State<Option<Arc<RateLimiter>>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(rate_limiter) = rate_limiter else {
+        return next.run(request).await;
+    };
+    let (client, authentication) = rate_limiter.client(&request);
+    match rate_limiter.take(client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            rate_limiter
+                .throttled
+                .with_label_values(&[authentication])
+                .inc();
+            tracing::warn!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Request rate limited",
+                name = "Request rate limited",
+                body = format!("An {authentication} client exceeded its rate limit"),
+                authentication = authentication,
+            );
+            // round up to whole seconds, as required by the header
+            let retry_after = wait
+                .as_secs()
+                .saturating_add(u64::from(wait.subsec_nanos() > 0))
+                .max(1);
+            let mut response = ErrorResponse::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_owned(),
+                json!({ "retryAfterSeconds": retry_after }),
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::{Body, HttpBody as _};
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use http::{header, Request, StatusCode};
+    use tower::ServiceExt as _;
+
+    use super::{rate_limit, RateLimit, RateLimiter, MAX_CLIENTS};
+
+    #[tokio::test]
+    async fn refills_the_bucket_over_time() {
+        let rate_limiter = RateLimiter::new(
+            RateLimit {
+                requests_per_second: 20.0,
+                burst: 2,
+            },
+            None,
+        );
+
+        assert!(rate_limiter.take("client".to_owned()).is_ok());
+        assert!(rate_limiter.take("client".to_owned()).is_ok());
+        let wait = rate_limiter.take("client".to_owned()).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50));
+
+        tokio::time::sleep(wait + Duration::from_millis(10)).await;
+        assert!(rate_limiter.take("client".to_owned()).is_ok());
+        assert!(rate_limiter.take("client".to_owned()).is_err());
+    }
+
+    #[test]
+    fn forgets_the_least_recently_used_clients() {
+        let rate_limiter = RateLimiter::new(
+            RateLimit {
+                requests_per_second: 0.001,
+                burst: 1,
+            },
+            None,
+        );
+
+        for client in 0..=2 * MAX_CLIENTS {
+            assert!(rate_limiter.take(client.to_string()).is_ok());
+        }
+
+        let buckets = rate_limiter.buckets.lock().unwrap();
+        assert!(buckets.buckets.len() <= MAX_CLIENTS);
+        assert!(buckets.buckets.contains_key(&(2 * MAX_CLIENTS).to_string()));
+        assert!(!buckets.buckets.contains_key("0"));
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_over_the_limit_with_retry_after() -> anyhow::Result<()> {
+        let rate_limiter = rate_limiter(None);
+        let router = router(&rate_limiter);
+        let from = |address: &str| request(address, None);
+
+        assert_eq!(
+            send(&router, from("192.0.2.1:1000")).await?.status(),
+            StatusCode::OK
+        );
+        let response = send(&router, from("192.0.2.1:1001")).await?;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // a token is added every thousand seconds
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1000");
+        let body = response.into_body().data().await.expect("no body")?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["details"]["retryAfterSeconds"], 1000);
+        assert_eq!(
+            rate_limiter
+                .throttled
+                .with_label_values(&["anonymous"])
+                .get(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_each_address_separately_without_authentication() -> anyhow::Result<()> {
+        let router = router(&rate_limiter(None));
+
+        assert_eq!(
+            send(&router, request("192.0.2.1:1000", None))
+                .await?
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&router, request("192.0.2.1:1001", None))
+                .await?
+                .status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            send(&router, request("192.0.2.2:1000", None))
+                .await?
+                .status(),
+            StatusCode::OK
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_each_token_separately() -> anyhow::Result<()> {
+        let rate_limiter = rate_limiter(Some("secret"));
+        let router = router(&rate_limiter);
+        let valid = Some("Bearer secret");
+        let invalid = Some("Bearer guess");
+
+        assert_eq!(
+            send(&router, request("192.0.2.1:1000", valid))
+                .await?
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&router, request("192.0.2.1:1001", valid))
+                .await?
+                .status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // another token from the same address has a bucket of its own
+        assert_eq!(
+            send(&router, request("192.0.2.1:1002", invalid))
+                .await?
+                .status(),
+            StatusCode::OK
+        );
+        // and the same token from another address shares its bucket
+        assert_eq!(
+            send(&router, request("192.0.2.2:1000", invalid))
+                .await?
+                .status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // the rejections are counted by whether the token was valid, rather than by client
+        for authentication in ["authenticated", "unauthenticated"] {
+            assert_eq!(
+                rate_limiter
+                    .throttled
+                    .with_label_values(&[authentication])
+                    .get(),
+                1
+            );
+        }
+        Ok(())
+    }
+
+    fn rate_limiter(service_token_secret: Option<&str>) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(
+            RateLimit {
+                requests_per_second: 0.001,
+                burst: 1,
+            },
+            service_token_secret,
+        ))
+    }
+
+    fn router(rate_limiter: &Arc<RateLimiter>) -> Router {
+        Router::new()
+            .route("/", get(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                Some(rate_limiter.clone()),
+                rate_limit,
+            ))
+    }
+
+    fn request(address: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request
+            .extension(ConnectInfo(address.parse::<SocketAddr>().unwrap()))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn send(
+        router: &Router,
+        request: Request<Body>,
+    ) -> anyhow::Result<http::Response<axum::body::BoxBody>> {
+        Ok(router.clone().oneshot(request).await?)
+    }
+}
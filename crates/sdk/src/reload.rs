@@ -0,0 +1,235 @@
+//! Reloading the configuration while the server is running.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::connector::Connector;
+use crate::state::ReloadableServerState;
+
+/// How often the configuration directory is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload the configuration, logging the outcome.
+///
+/// If the configuration cannot be parsed or the state cannot be initialized, the current
+/// generation of the server state continues to serve requests.
+pub async fn reload_configuration<C>(state: &ReloadableServerState<C>, config_directory: &Path)
+where
+    C: Connector,
+    C::Configuration: Clone,
+{
+    match state.reload(config_directory).await {
+        Ok(generation) => {
+            tracing::info!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Configuration reloaded",
+                name = "Configuration reloaded",
+                body = format!("Now serving configuration generation {generation}"),
+                generation,
+            );
+        }
+        Err(err) => {
+            tracing::error!(
+                meta.signal_type = "log",
+                event.domain = "ndc",
+                event.name = "Configuration reload failed",
+                name = "Configuration reload failed",
+                body = %err,
+                error = true,
+            );
+        }
+    }
+}
+
+/// Reload the configuration each time the process receives a SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup<C>(state: ReloadableServerState<C>, config_directory: PathBuf)
+where
+    C: Connector,
+    C::Configuration: Clone,
+{
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while sighup.recv().await.is_some() {
+        reload_configuration(&state, &config_directory).await;
+    }
+}
+
+/// Reload the configuration each time a file in the configuration directory changes.
+///
+/// The directory is polled, comparing the paths, sizes and modification times of the files within
+/// it. Symbolic links to files are followed, so that swapping out a mounted volume is detected.
+pub async fn watch_configuration<C>(state: ReloadableServerState<C>, config_directory: PathBuf)
+where
+    C: Connector,
+    C::Configuration: Clone,
+{
+    let fingerprint = fingerprint(&config_directory).await;
+    watch_configuration_every(state, config_directory, fingerprint, WATCH_INTERVAL).await;
+}
+
+/// Poll the configuration directory for changes since it had the given fingerprint.
+async fn watch_configuration_every<C>(
+    state: ReloadableServerState<C>,
+    config_directory: PathBuf,
+    mut last_fingerprint: Fingerprint,
+    period: Duration,
+) where
+    C: Connector,
+    C::Configuration: Clone,
+{
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let fingerprint = fingerprint(&config_directory).await;
+        if fingerprint != last_fingerprint {
+            last_fingerprint = fingerprint;
+            reload_configuration(&state, &config_directory).await;
+        }
+    }
+}
+
+type Fingerprint = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+async fn fingerprint(config_directory: &Path) -> Fingerprint {
+    let config_directory = config_directory.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut fingerprint = Vec::new();
+        collect_fingerprint(&config_directory, &mut fingerprint);
+        fingerprint.sort();
+        fingerprint
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn collect_fingerprint(directory: &Path, fingerprint: &mut Fingerprint) {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // we only recurse into real directories, to avoid following cycles of links
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            collect_fingerprint(&path, fingerprint);
+        } else if let Ok(metadata) = std::fs::metadata(&path) {
+            fingerprint.push((path, metadata.len(), metadata.modified().ok()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use super::{fingerprint, watch_configuration_every};
+    use crate::connector::example::Example;
+    use crate::connector::{Connector, ConnectorSetup, ErrorResponse, Result};
+    use crate::state::{init_server_state, ReloadableServerState};
+    use crate::test_support::eventually;
+
+    /// Sets up the example connector, failing to initialize its state while the `state` file in
+    /// the configuration directory says it is unavailable.
+    #[derive(Clone)]
+    struct TestSetup {
+        config_directory: PathBuf,
+        init_attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ConnectorSetup for TestSetup {
+        type Connector = Example;
+
+        async fn parse_configuration(&self, _configuration_dir: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        async fn try_init_state(
+            &self,
+            _configuration: &<Example as Connector>::Configuration,
+            _metrics: &mut prometheus::Registry,
+        ) -> Result<<Example as Connector>::State> {
+            self.init_attempts.fetch_add(1, Ordering::AcqRel);
+            let state = std::fs::read_to_string(self.config_directory.join("state"))
+                .map_err(ErrorResponse::from_error)?;
+            if state == "unavailable" {
+                return Err(ErrorResponse::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The state is unavailable".to_owned(),
+                    json!({}),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /// Watch a new configuration directory, whose `state` file says the state is available.
+    async fn watch(name: &str) -> anyhow::Result<(TestSetup, ReloadableServerState<Example>)> {
+        let config_directory =
+            std::env::temp_dir().join(format!("ndc-sdk-reload-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&config_directory)?;
+        std::fs::write(config_directory.join("state"), "available")?;
+        let setup = TestSetup {
+            config_directory: config_directory.clone(),
+            init_attempts: Arc::new(AtomicUsize::new(0)),
+        };
+        let state =
+            ReloadableServerState::from(init_server_state(setup.clone(), &config_directory).await?);
+        state.current().state().await?;
+        // the changes the tests make must come after the first fingerprint to be seen
+        let fingerprint = fingerprint(&config_directory).await;
+        tokio::spawn(watch_configuration_every(
+            state.clone(),
+            config_directory,
+            fingerprint,
+            Duration::from_millis(10),
+        ));
+        Ok((setup, state))
+    }
+
+    #[tokio::test]
+    async fn reloads_the_configuration_when_a_file_changes() -> anyhow::Result<()> {
+        let (setup, state) = watch("changes").await?;
+        assert_eq!(state.current().generation(), 0);
+
+        std::fs::write(setup.config_directory.join("configuration.json"), "{}")?;
+        eventually(|| state.current().generation() == 1).await;
+
+        std::fs::write(
+            setup.config_directory.join("configuration.json"),
+            r#"{"changed":true}"#,
+        )?;
+        eventually(|| state.current().generation() == 2).await;
+
+        std::fs::remove_dir_all(&setup.config_directory)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_the_previous_state_if_the_new_state_fails() -> anyhow::Result<()> {
+        let (setup, state) = watch("fails").await?;
+        assert_eq!(setup.init_attempts.load(Ordering::Acquire), 1);
+
+        std::fs::write(setup.config_directory.join("state"), "unavailable")?;
+        eventually(|| setup.init_attempts.load(Ordering::Acquire) == 2).await;
+        let current = state.current();
+        assert_eq!(current.generation(), 0);
+        assert!(current.state().await.is_ok());
+
+        // a later change which succeeds is still picked up
+        std::fs::write(setup.config_directory.join("state"), "available")?;
+        eventually(|| state.current().generation() == 1).await;
+
+        std::fs::remove_dir_all(&setup.config_directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Helpers shared by the tests of several modules.
+
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::connector::example::Example;
+use crate::connector::{Connector, ConnectorSetup, Result};
+use crate::json_response::JsonResponse;
+use crate::models;
+use crate::request_context::RequestContext;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Handles a query in place of the example connector, given the request's context.
+pub type QueryOverride = Arc<
+    dyn Fn(&RequestContext) -> BoxFuture<Result<JsonResponse<models::QueryResponse>>> + Send + Sync,
+>;
+
+/// Updates the metrics in place of the example connector.
+pub type FetchMetricsOverride = Arc<dyn Fn() -> BoxFuture<Result<()>> + Send + Sync>;
+
+/// A connector which behaves as the example connector, except where a test overrides it.
+///
+/// The connector is also its own state, so that its methods can reach the overrides.
+#[derive(Clone, Default)]
+pub struct TestConnector {
+    pub query: Option<QueryOverride>,
+    pub fetch_metrics: Option<FetchMetricsOverride>,
+}
+
+#[async_trait]
+impl ConnectorSetup for TestConnector {
+    type Connector = Self;
+
+    async fn parse_configuration(&self, configuration_dir: &Path) -> Result<()> {
+        Example {}.parse_configuration(configuration_dir).await
+    }
+
+    async fn try_init_state(
+        &self,
+        _configuration: &(),
+        _metrics: &mut prometheus::Registry,
+    ) -> Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+#[async_trait]
+impl Connector for TestConnector {
+    type Configuration = ();
+    type State = Self;
+
+    async fn fetch_metrics_async(configuration: &(), state: &Self) -> Result<()> {
+        match &state.fetch_metrics {
+            Some(fetch_metrics) => fetch_metrics().await,
+            None => Example::fetch_metrics_async(configuration, &()).await,
+        }
+    }
+
+    async fn get_capabilities() -> models::Capabilities {
+        Example::get_capabilities().await
+    }
+
+    async fn get_schema(configuration: &()) -> Result<JsonResponse<models::SchemaResponse>> {
+        Example::get_schema(configuration).await
+    }
+
+    async fn query_explain(
+        configuration: &(),
+        _state: &Self,
+        request_context: &RequestContext,
+        request: models::QueryRequest,
+    ) -> Result<JsonResponse<models::ExplainResponse>> {
+        Example::query_explain(configuration, &(), request_context, request).await
+    }
+
+    async fn mutation_explain(
+        configuration: &(),
+        _state: &Self,
+        request_context: &RequestContext,
+        request: models::MutationRequest,
+    ) -> Result<JsonResponse<models::ExplainResponse>> {
+        Example::mutation_explain(configuration, &(), request_context, request).await
+    }
+
+    async fn mutation(
+        configuration: &(),
+        _state: &Self,
+        request_context: &RequestContext,
+        request: models::MutationRequest,
+    ) -> Result<JsonResponse<models::MutationResponse>> {
+        Example::mutation(configuration, &(), request_context, request).await
+    }
+
+    async fn query(
+        configuration: &(),
+        state: &Self,
+        request_context: &RequestContext,
+        request: models::QueryRequest,
+    ) -> Result<JsonResponse<models::QueryResponse>> {
+        match &state.query {
+            Some(query) => query(request_context).await,
+            None => Example::query(configuration, &(), request_context, request).await,
+        }
+    }
+}
+
+/// Wait up to five seconds for a condition to hold, panicking if it does not.
+pub async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the condition was not met in time");
+}
@@ -1,24 +1,60 @@
 use std::borrow::ToOwned;
 use std::env;
 use std::error::Error;
-use std::time::Duration;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 
 use axum::body::{Body, BoxBody};
 use http::{Request, Response};
-use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
-use tracing::{Level, Span};
-use tracing_opentelemetry::OpenTelemetrySpanExt;
-use tracing_subscriber::layer::SubscriberExt;
+use opentelemetry::metrics::Unit;
+use opentelemetry::KeyValue;
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{
+    HttpExporterBuilder, LogExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder,
+    TonicExporterBuilder, WithExportConfig,
+};
+use opentelemetry_sdk::logs::LoggerProvider;
+use opentelemetry_sdk::metrics::data::{self, Aggregation, ScopeMetrics, Temporality};
+use opentelemetry_sdk::metrics::reader::{
+    DefaultAggregationSelector, DefaultTemporalitySelector, MetricProducer,
+};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::resource::{OsResourceDetector, TelemetryResourceDetector};
+use opentelemetry_sdk::trace::Sampler;
+use opentelemetry_sdk::AttributeSet;
+use opentelemetry_sdk::{Resource, Scope};
+use opentelemetry_semantic_conventions::resource;
+use prometheus::proto::{MetricFamily, MetricType};
+use tracing::{Event, Level, Metadata, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData, PreSampledTracer};
+use tracing_subscriber::filter;
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::util::SubscriberInitExt;
 
+use crate::connector::ConnectorSetup;
+
+/// Set up logging as JSON to stdout, and the export of traces and logs to the OTLP endpoint.
+///
+/// Traces are exported if an endpoint is configured, sampled by `sampler`. Logs are exported too if
+/// `logs_exporter` is OTLP, carrying the trace and span ids of the request they were logged in.
+/// Both are described by `resource`, which is typically the [`connector_resource`].
 pub fn init_tracing(
-    service_name: Option<&str>,
+    resource: &Resource,
     otlp_endpoint: Option<&str>,
+    logs_exporter: Exporter,
+    sampler: Sampler,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let trace_endpoint = otlp_endpoint
         .map(ToOwned::to_owned)
         .or_else(|| env::var(opentelemetry_otlp::OTEL_EXPORTER_OTLP_TRACES_ENDPOINT).ok());
-
+    let logs_endpoint = match logs_exporter {
+        Exporter::Otlp => otlp_endpoint
+            .map(ToOwned::to_owned)
+            .or_else(|| env::var(opentelemetry_otlp::OTEL_EXPORTER_OTLP_LOGS_ENDPOINT).ok()),
+        Exporter::None => None,
+    };
     let log_level = env::var("RUST_LOG").unwrap_or(Level::INFO.to_string());
     let subscriber = tracing_subscriber::registry()
         .with(
@@ -31,9 +67,9 @@ pub fn init_tracing(
                 .with_timer(tracing_subscriber::fmt::time::time()),
         );
 
-    match trace_endpoint {
-        // disable traces exporter if the endpoint is empty
-        None => subscriber.init(),
+    // disable traces exporter if the endpoint is empty
+    let tracer = match trace_endpoint {
+        None => None,
         Some(endpoint) => {
             opentelemetry::global::set_text_map_propagator(
                 opentelemetry::propagation::composite::TextMapCompositePropagator::new(vec![
@@ -42,64 +78,584 @@ pub fn init_tracing(
                 ]),
             );
 
-            let service_name = service_name.unwrap_or(env!("CARGO_PKG_NAME"));
-
-            let exporter: SpanExporterBuilder =
-                match env::var(opentelemetry_otlp::OTEL_EXPORTER_OTLP_PROTOCOL) {
-                    Ok(protocol) => match protocol.as_str() {
-                        "grpc" => Ok(opentelemetry_otlp::new_exporter()
-                            .tonic()
-                            .with_endpoint(endpoint)
-                            .into()),
-                        "http/protobuf" => Ok(opentelemetry_otlp::new_exporter()
-                            .http()
-                            .with_endpoint(endpoint)
-                            .into()),
-                        invalid => Err(format!("invalid protocol: {invalid:?}")),
-                    },
-                    // the default exporter protocol is grpc
-                    Err(env::VarError::NotPresent) => Ok(opentelemetry_otlp::new_exporter()
-                        .tonic()
-                        .with_endpoint(endpoint)
-                        .into()),
-                    Err(env::VarError::NotUnicode(os_str)) => {
-                        Err(format!("invalid protocol: {os_str:?}"))
-                    }
-                }?;
+            let exporter: SpanExporterBuilder = OtlpProtocol::from_env()?.exporter(&endpoint);
 
             let tracer = opentelemetry_otlp::new_pipeline()
                 .tracing()
                 .with_exporter(exporter)
                 .with_trace_config(
                     opentelemetry_sdk::trace::config()
-                        .with_resource(opentelemetry_sdk::Resource::new(vec![
-                            opentelemetry::KeyValue::new(
-                                opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                                service_name.to_string(),
-                            ),
-                            opentelemetry::KeyValue::new(
-                                opentelemetry_semantic_conventions::resource::SERVICE_VERSION,
-                                env!("CARGO_PKG_VERSION"),
-                            ),
-                        ]))
-                        .with_sampler(opentelemetry_sdk::trace::Sampler::ParentBased(Box::new(
-                            opentelemetry_sdk::trace::Sampler::AlwaysOn,
-                        ))),
+                        .with_resource(resource.clone())
+                        .with_sampler(sampler),
                 )
                 .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(tracer)
+        }
+    };
 
-            subscriber
-                .with(
-                    tracing_opentelemetry::layer()
-                        .with_error_records_to_exceptions(true)
-                        .with_tracer(tracer),
-                )
-                .init();
+    let log_export = match logs_endpoint {
+        None => None,
+        Some(endpoint) => {
+            let exporter = OtlpProtocol::from_env()?
+                .exporter::<LogExporterBuilder>(&endpoint)
+                .build_log_exporter()?;
+            let logger_provider = LoggerProvider::builder()
+                .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                .with_config(opentelemetry_sdk::logs::config().with_resource(resource.clone()))
+                .build();
+            let log_export = LogExport {
+                bridge: OpenTelemetryTracingBridge::new(&logger_provider),
+                tracer: tracer.clone(),
+            };
+            // kept so that the logs can be flushed on shutdown
+            let _ = LOGGER_PROVIDER.set(logger_provider);
+            Some(log_export.with_filter(filter::filter_fn(is_exported_log)))
         }
     };
 
+    subscriber
+        .with(tracer.map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_error_records_to_exceptions(true)
+                .with_tracer(tracer)
+        }))
+        .with(log_export)
+        .init();
+
     Ok(())
 }
+
+/// Export the traces and logs which have not been exported yet, when the server stops.
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+    if let Some(logger_provider) = LOGGER_PROVIDER.get() {
+        for result in logger_provider.force_flush() {
+            if let Err(err) = result {
+                opentelemetry::global::handle_error(err);
+            }
+        }
+    }
+}
+
+static LOGGER_PROVIDER: OnceLock<LoggerProvider> = OnceLock::new();
+
+/// Exports `tracing` events as OpenTelemetry logs.
+///
+/// The log bridge takes the trace and span ids from the current OpenTelemetry context, which
+/// `tracing` spans do not set, so the context of the event's span is attached while it is logged.
+struct LogExport {
+    bridge: OpenTelemetryTracingBridge<LoggerProvider, opentelemetry_sdk::logs::Logger>,
+    tracer: Option<opentelemetry_sdk::trace::Tracer>,
+}
+
+impl<S> Layer<S> for LogExport
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: layer::Context<'_, S>) {
+        let otel_context = self.tracer.as_ref().and_then(|tracer| {
+            let span = ctx.event_span(event)?;
+            let mut extensions = span.extensions_mut();
+            let otel_data = extensions.get_mut::<OtelData>()?;
+            Some(tracer.sampled_context(otel_data))
+        });
+        let _guard = otel_context.map(opentelemetry::Context::attach);
+        self.bridge.on_event(event, ctx);
+    }
+}
+
+/// Whether an event is exported as a log. Events logged by the libraries which export telemetry,
+/// or by their modules, are not, as they may be logged while exporting logs.
+fn is_exported_log(metadata: &Metadata<'_>) -> bool {
+    const EXPORTER_TARGETS: [&str; 6] =
+        ["h2", "hyper", "opentelemetry", "reqwest", "tonic", "tower"];
+    let target = metadata.target();
+    !EXPORTER_TARGETS.iter().any(|exporter_target| {
+        target
+            .strip_prefix(exporter_target)
+            .is_some_and(|module| module.is_empty() || module.starts_with("::"))
+    })
+}
+
+/// Which traces to sample, as set by the standard `OTEL_TRACES_SAMPLER` variable.
+///
+/// The parent-based samplers follow the sampling decision of the caller for requests which are
+/// part of a trace, and only decide for themselves for the root spans of new traces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TracesSampler {
+    /// Sample every trace.
+    AlwaysOn,
+    /// Sample no traces.
+    AlwaysOff,
+    /// Sample a ratio of traces, by trace id.
+    TraceIdRatio,
+    /// Sample every new trace.
+    #[default]
+    ParentBasedAlwaysOn,
+    /// Sample no new traces.
+    ParentBasedAlwaysOff,
+    /// Sample a ratio of new traces, by trace id.
+    ParentBasedTraceIdRatio,
+}
+
+impl TracesSampler {
+    /// The sampler chosen by the values of `OTEL_TRACES_SAMPLER` and `OTEL_TRACES_SAMPLER_ARG`,
+    /// along with the variable and a warning if either is unsupported.
+    ///
+    /// Unsupported samplers, such as `jaeger_remote` or `xray`, are replaced by the default, and
+    /// ratios which are not between 0 and 1 by 1, so that settings shared with other services do
+    /// not stop the connector from starting. The ratio is only used by the ratio samplers.
+    pub fn parse(sampler: &str, arg: Option<&str>) -> (Sampler, Option<(&'static str, String)>) {
+        let ((traces_sampler, ratio), warning) = Self::choose(sampler, arg);
+        (traces_sampler.sampler(ratio), warning)
+    }
+
+    /// The sampler and its ratio, as chosen by [`TracesSampler::parse`].
+    fn choose(sampler: &str, arg: Option<&str>) -> ((Self, f64), Option<(&'static str, String)>) {
+        let traces_sampler = match sampler.trim().to_ascii_lowercase().as_str() {
+            "always_on" => Self::AlwaysOn,
+            "always_off" => Self::AlwaysOff,
+            "traceidratio" => Self::TraceIdRatio,
+            "parentbased_always_on" => Self::ParentBasedAlwaysOn,
+            "parentbased_always_off" => Self::ParentBasedAlwaysOff,
+            "parentbased_traceidratio" => Self::ParentBasedTraceIdRatio,
+            _ => {
+                let warning = format!("Unsupported sampler is ignored: {sampler}");
+                return (
+                    (Self::default(), 1.0),
+                    Some(("OTEL_TRACES_SAMPLER", warning)),
+                );
+            }
+        };
+        if !matches!(
+            traces_sampler,
+            Self::TraceIdRatio | Self::ParentBasedTraceIdRatio
+        ) {
+            return ((traces_sampler, 1.0), None);
+        }
+        let ratio = arg.map_or(Some(1.0), |arg| {
+            arg.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+        });
+        let Some(ratio) = ratio else {
+            let warning = format!(
+                "The ratio must be between 0 and 1, so 1 is used instead of {}",
+                arg.unwrap_or_default()
+            );
+            return (
+                (traces_sampler, 1.0),
+                Some(("OTEL_TRACES_SAMPLER_ARG", warning)),
+            );
+        };
+        ((traces_sampler, ratio), None)
+    }
+
+    /// The sampler, which samples `ratio` of traces if it is a ratio sampler.
+    pub fn sampler(self, ratio: f64) -> Sampler {
+        match self {
+            Self::AlwaysOn => Sampler::AlwaysOn,
+            Self::AlwaysOff => Sampler::AlwaysOff,
+            Self::TraceIdRatio => Sampler::TraceIdRatioBased(ratio),
+            Self::ParentBasedAlwaysOn => Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            Self::ParentBasedAlwaysOff => Sampler::ParentBased(Box::new(Sampler::AlwaysOff)),
+            Self::ParentBasedTraceIdRatio => {
+                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+            }
+        }
+    }
+}
+
+/// Which exporter to use for a signal, as set by the standard `OTEL_*_EXPORTER` variables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Exporter {
+    /// Export over OTLP, to the same endpoint as traces.
+    Otlp,
+    /// Do not export.
+    #[default]
+    None,
+}
+
+impl Exporter {
+    /// The exporter chosen by the value of an `OTEL_*_EXPORTER` variable, which is a
+    /// comma-separated list of exporter names, along with a warning if any of them are unsupported.
+    ///
+    /// Only OTLP is supported. Other exporters, such as `console` or `prometheus`, are ignored, so
+    /// that settings shared with other services do not stop the connector from starting.
+    pub fn parse(value: &str) -> (Self, Option<String>) {
+        let mut exporter = Self::default();
+        let mut unsupported = vec![];
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name.to_ascii_lowercase().as_str() {
+                "otlp" => exporter = Self::Otlp,
+                "none" => {}
+                _ => unsupported.push(name),
+            }
+        }
+        let warning = (!unsupported.is_empty()).then(|| {
+            format!(
+                "Unsupported exporters are ignored: {}",
+                unsupported.join(", ")
+            )
+        });
+        (exporter, warning)
+    }
+}
+
+/// Periodically export metrics over OTLP, to the same endpoint as traces.
+///
+/// Each export gathers the metrics afresh with `gather`, which typically gathers the server's
+/// metrics registry, holding both the connector's metrics and the SDK's own, along with the time
+/// the registry was created. Cumulative metrics are exported as counting from that time, so that
+/// when the registry is replaced, the collector sees them restart rather than go backwards.
+///
+/// Metrics are exported every `OTEL_METRIC_EXPORT_INTERVAL` milliseconds, or every minute by
+/// default. Metrics which the connector only updates in `fetch_metrics` are exported with the
+/// values from the last scrape.
+///
+/// If no endpoint is configured, nothing is exported and `None` is returned. Otherwise, the meter
+/// provider should be shut down when the server stops, to export the final values.
+pub fn init_metrics_export(
+    resource: &Resource,
+    otlp_endpoint: Option<&str>,
+    gather: impl Fn() -> (SystemTime, Vec<MetricFamily>) + Send + Sync + 'static,
+) -> Result<Option<SdkMeterProvider>, Box<dyn Error + Send + Sync>> {
+    let metrics_endpoint = otlp_endpoint
+        .map(ToOwned::to_owned)
+        .or_else(|| env::var(opentelemetry_otlp::OTEL_EXPORTER_OTLP_METRICS_ENDPOINT).ok());
+    let Some(endpoint) = metrics_endpoint else {
+        return Ok(None);
+    };
+    Ok(Some(metrics_export(
+        resource,
+        &endpoint,
+        OtlpProtocol::from_env()?,
+        gather,
+    )?))
+}
+
+fn metrics_export(
+    resource: &Resource,
+    endpoint: &str,
+    protocol: OtlpProtocol,
+    gather: impl Fn() -> (SystemTime, Vec<MetricFamily>) + Send + Sync + 'static,
+) -> Result<SdkMeterProvider, Box<dyn Error + Send + Sync>> {
+    let exporter = protocol
+        .exporter::<MetricsExporterBuilder>(endpoint)
+        .build_metrics_exporter(
+            Box::new(DefaultTemporalitySelector::new()),
+            Box::new(DefaultAggregationSelector::new()),
+        )?;
+    let reader = PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_producer(PrometheusProducer {
+            gather: Box::new(gather),
+        })
+        .build();
+    Ok(SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource.clone())
+        .build())
+}
+
+/// The protocol with which telemetry is exported over OTLP.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OtlpProtocol {
+    #[default]
+    Grpc,
+    HttpProtobuf,
+}
+
+impl OtlpProtocol {
+    /// The protocol set by `OTEL_EXPORTER_OTLP_PROTOCOL`, which is gRPC by default.
+    fn from_env() -> Result<Self, String> {
+        match env::var(opentelemetry_otlp::OTEL_EXPORTER_OTLP_PROTOCOL) {
+            Ok(protocol) => match protocol.as_str() {
+                "grpc" => Ok(Self::Grpc),
+                "http/protobuf" => Ok(Self::HttpProtobuf),
+                invalid => Err(format!("invalid protocol: {invalid:?}")),
+            },
+            Err(env::VarError::NotPresent) => Ok(Self::default()),
+            Err(env::VarError::NotUnicode(os_str)) => Err(format!("invalid protocol: {os_str:?}")),
+        }
+    }
+
+    /// An OTLP exporter builder for this protocol.
+    fn exporter<B>(self, endpoint: &str) -> B
+    where
+        B: From<TonicExporterBuilder> + From<HttpExporterBuilder>,
+    {
+        match self {
+            Self::Grpc => opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .into(),
+            Self::HttpProtobuf => opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint)
+                .into(),
+        }
+    }
+}
+
+/// The resource describing the connector in the telemetry it exports.
+///
+/// Attributes are taken from, in increasing order of precedence: the host, the process and the
+/// OpenTelemetry SDK; the connector's name, version and resource attributes, as supplied by its
+/// setup; `OTEL_RESOURCE_ATTRIBUTES`; and `service_name`, as set by `OTEL_SERVICE_NAME`.
+///
+/// The process's command line is left out, as it may include secrets.
+pub fn connector_resource(setup: &impl ConnectorSetup, service_name: Option<&str>) -> Resource {
+    resource_from(setup, service_name, |name| env::var(name).ok())
+}
+
+/// The resource describing the connector, reading environment variables with `env_var`.
+fn resource_from(
+    setup: &impl ConnectorSetup,
+    service_name: Option<&str>,
+    env_var: impl Fn(&str) -> Option<String>,
+) -> Resource {
+    let detected = Resource::from_detectors(
+        Duration::from_secs(1),
+        vec![
+            Box::new(OsResourceDetector),
+            Box::new(TelemetryResourceDetector),
+        ],
+    )
+    .merge(&Resource::new(
+        [
+            KeyValue::new(resource::HOST_ARCH, host_arch()),
+            KeyValue::new(resource::PROCESS_PID, i64::from(std::process::id())),
+        ]
+        .into_iter()
+        .chain(host_name(&env_var).map(|host_name| KeyValue::new(resource::HOST_NAME, host_name))),
+    ));
+    let connector = Resource::new(
+        [
+            KeyValue::new(
+                resource::SERVICE_NAME,
+                setup
+                    .connector_name()
+                    .unwrap_or(env!("CARGO_PKG_NAME"))
+                    .to_owned(),
+            ),
+            KeyValue::new(
+                resource::SERVICE_VERSION,
+                setup
+                    .connector_version()
+                    .unwrap_or(env!("CARGO_PKG_VERSION"))
+                    .to_owned(),
+            ),
+        ]
+        .into_iter()
+        .chain(
+            setup
+                .resource_attributes()
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value)),
+        ),
+    );
+    // parsed as by the SDK's `EnvResourceDetector`, as comma-separated `key=value` pairs
+    let from_env = Resource::new(
+        env_var("OTEL_RESOURCE_ATTRIBUTES")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|attribute| {
+                let (key, value) = attribute.split_once('=')?;
+                if value.contains('=') {
+                    return None;
+                }
+                Some(KeyValue::new(
+                    key.trim().to_owned(),
+                    value.trim().to_owned(),
+                ))
+            })
+            .collect::<Vec<_>>(),
+    );
+    let service_name = Resource::new(
+        service_name
+            .map(|service_name| KeyValue::new(resource::SERVICE_NAME, service_name.to_owned())),
+    );
+    detected
+        .merge(&connector)
+        .merge(&from_env)
+        .merge(&service_name)
+}
+
+/// The name of the host, as set by the shell or the container runtime in `HOSTNAME`, or else as
+/// configured in `/etc/hostname`.
+fn host_name(env_var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    env_var("HOSTNAME")
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|host_name| host_name.trim().to_owned())
+        .filter(|host_name| !host_name.is_empty())
+}
+
+/// The architecture of the host, as named by the OpenTelemetry semantic conventions.
+fn host_arch() -> &'static str {
+    match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64",
+        arch => arch,
+    }
+}
+
+/// Converts metrics gathered from a Prometheus registry to OpenTelemetry metrics on each export.
+///
+/// Counters and histograms are cumulative, as in Prometheus. Counters lose their `_total` suffix,
+/// which the collector adds back when exporting to Prometheus. Summaries have no equivalent, and
+/// are not exported. Prometheus metrics have no units, so these are derived from the names of those
+/// which follow its conventions for seconds and bytes.
+struct PrometheusProducer {
+    // gathers the metrics, with the time from which the cumulative ones have been counting
+    gather: Box<dyn Fn() -> (SystemTime, Vec<MetricFamily>) + Send + Sync>,
+}
+
+impl fmt::Debug for PrometheusProducer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusProducer").finish_non_exhaustive()
+    }
+}
+
+impl MetricProducer for PrometheusProducer {
+    fn produce(&self) -> opentelemetry::metrics::Result<ScopeMetrics> {
+        let now = SystemTime::now();
+        let (start_time, metric_families) = (self.gather)();
+        let times = (start_time, now);
+        let metrics = metric_families
+            .iter()
+            .filter_map(|family| {
+                let data: Box<dyn Aggregation> = match family.get_field_type() {
+                    MetricType::COUNTER => Box::new(data::Sum {
+                        data_points: data_points(family, times, |metric| {
+                            metric.get_counter().get_value()
+                        }),
+                        temporality: Temporality::Cumulative,
+                        is_monotonic: true,
+                    }),
+                    MetricType::GAUGE => Box::new(data::Gauge {
+                        data_points: data_points(family, times, |metric| {
+                            metric.get_gauge().get_value()
+                        }),
+                    }),
+                    MetricType::UNTYPED => Box::new(data::Gauge {
+                        data_points: data_points(family, times, |metric| {
+                            metric.get_untyped().get_value()
+                        }),
+                    }),
+                    MetricType::HISTOGRAM => Box::new(data::Histogram {
+                        data_points: family
+                            .get_metric()
+                            .iter()
+                            .map(|metric| histogram_data_point(metric, times))
+                            .collect(),
+                        temporality: Temporality::Cumulative,
+                    }),
+                    MetricType::SUMMARY => return None,
+                };
+                let name = family.get_name();
+                let name = match family.get_field_type() {
+                    MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+                    _ => name,
+                };
+                Some(data::Metric {
+                    name: name.to_owned().into(),
+                    description: family.get_help().to_owned().into(),
+                    unit: Unit::new(unit(name)),
+                    data,
+                })
+            })
+            .collect();
+        Ok(ScopeMetrics {
+            scope: Scope::new(
+                env!("CARGO_PKG_NAME"),
+                Some(env!("CARGO_PKG_VERSION")),
+                None::<&str>,
+                None,
+            ),
+            metrics,
+        })
+    }
+}
+
+/// The unit of a metric, in UCUM as OpenTelemetry expects, going by the suffix of its name.
+fn unit(name: &str) -> &'static str {
+    if name.ends_with("_seconds") {
+        "s"
+    } else if name.ends_with("_bytes") {
+        "By"
+    } else {
+        ""
+    }
+}
+
+/// The data points of a counter or gauge, over the period between the times.
+fn data_points(
+    family: &MetricFamily,
+    (start_time, now): (SystemTime, SystemTime),
+    value: impl Fn(&prometheus::proto::Metric) -> f64,
+) -> Vec<data::DataPoint<f64>> {
+    family
+        .get_metric()
+        .iter()
+        .map(|metric| data::DataPoint {
+            attributes: attributes(metric),
+            start_time: Some(start_time),
+            time: Some(now),
+            value: value(metric),
+            exemplars: vec![],
+        })
+        .collect()
+}
+
+fn histogram_data_point(
+    metric: &prometheus::proto::Metric,
+    (start_time, now): (SystemTime, SystemTime),
+) -> data::HistogramDataPoint<f64> {
+    let histogram = metric.get_histogram();
+    // Prometheus buckets are cumulative, and OpenTelemetry buckets are not, with the +Inf bucket
+    // left implicit
+    let mut bounds = vec![];
+    let mut bucket_counts = vec![];
+    let mut previous_count = 0;
+    for bucket in histogram.get_bucket() {
+        if bucket.get_upper_bound().is_finite() {
+            bounds.push(bucket.get_upper_bound());
+            bucket_counts.push(bucket.get_cumulative_count().saturating_sub(previous_count));
+            previous_count = bucket.get_cumulative_count();
+        }
+    }
+    bucket_counts.push(histogram.get_sample_count().saturating_sub(previous_count));
+    data::HistogramDataPoint {
+        attributes: attributes(metric),
+        start_time,
+        time: now,
+        count: histogram.get_sample_count(),
+        bounds,
+        bucket_counts,
+        min: None,
+        max: None,
+        sum: histogram.get_sample_sum(),
+        exemplars: vec![],
+    }
+}
+
+fn attributes(metric: &prometheus::proto::Metric) -> AttributeSet {
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|label| {
+            opentelemetry::KeyValue::new(label.get_name().to_owned(), label.get_value().to_owned())
+        })
+        .collect::<Vec<_>>();
+    AttributeSet::from(labels.as_slice())
+}
+
 // Custom function for creating request-level spans
 // tracing crate requires all fields to be defined at creation time, so any fields that will be set
 // later should be defined as Empty
@@ -137,3 +693,321 @@ pub fn on_response(response: &Response<BoxBody>, latency: Duration, span: &Span)
     span.record("status", tracing::field::display(response.status()));
     span.record("latency", tracing::field::display(latency.as_nanos()));
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+
+    use async_trait::async_trait;
+    use axum::body::Bytes;
+    use axum::routing::post;
+    use opentelemetry::{Key, Value};
+    use prometheus::{IntCounter, Registry};
+
+    use crate::connector::example::Example;
+    use crate::connector::{Connector, Result};
+
+    use super::*;
+
+    struct TestSetup;
+
+    #[async_trait]
+    impl ConnectorSetup for TestSetup {
+        type Connector = Example;
+
+        async fn parse_configuration(
+            &self,
+            configuration_dir: &Path,
+        ) -> Result<<Example as Connector>::Configuration> {
+            Example::default()
+                .parse_configuration(configuration_dir)
+                .await
+        }
+
+        async fn try_init_state(
+            &self,
+            configuration: &<Example as Connector>::Configuration,
+            metrics: &mut Registry,
+        ) -> Result<<Example as Connector>::State> {
+            Example::default()
+                .try_init_state(configuration, metrics)
+                .await
+        }
+
+        fn connector_name(&self) -> Option<&str> {
+            Some("ndc-test-connector")
+        }
+
+        fn connector_version(&self) -> Option<&str> {
+            Some("1.2.3")
+        }
+
+        fn resource_attributes(&self) -> Vec<(String, String)> {
+            vec![
+                ("service.namespace".to_owned(), "tests".to_owned()),
+                ("deployment.environment".to_owned(), "staging".to_owned()),
+            ]
+        }
+    }
+
+    #[test]
+    fn describes_the_connector_in_its_resource() {
+        let env_var = |name: &str| match name {
+            "OTEL_RESOURCE_ATTRIBUTES" => {
+                Some("deployment.environment=production, team = data".to_owned())
+            }
+            "HOSTNAME" => Some("connector-7f9c".to_owned()),
+            _ => None,
+        };
+        let resource = resource_from(&TestSetup, None, env_var);
+        let renamed = resource_from(&TestSetup, Some("renamed-connector"), env_var);
+
+        let get = |resource: &Resource, key: &'static str| {
+            resource
+                .get(Key::from_static_str(key))
+                .map(|value| value.to_string())
+        };
+        assert_eq!(
+            get(&resource, resource::SERVICE_NAME).as_deref(),
+            Some("ndc-test-connector")
+        );
+        assert_eq!(
+            get(&resource, resource::SERVICE_VERSION).as_deref(),
+            Some("1.2.3")
+        );
+        assert_eq!(
+            get(&resource, "service.namespace").as_deref(),
+            Some("tests")
+        );
+        assert_eq!(
+            get(&resource, "deployment.environment").as_deref(),
+            Some("production")
+        );
+        assert_eq!(get(&resource, "team").as_deref(), Some("data"));
+        assert_eq!(
+            get(&resource, resource::HOST_NAME).as_deref(),
+            Some("connector-7f9c")
+        );
+        assert_eq!(
+            resource.get(Key::from_static_str(resource::PROCESS_PID)),
+            Some(Value::I64(i64::from(std::process::id())))
+        );
+        assert_eq!(get(&resource, resource::PROCESS_COMMAND_ARGS), None);
+        assert_eq!(
+            get(&renamed, resource::SERVICE_NAME).as_deref(),
+            Some("renamed-connector")
+        );
+    }
+
+    #[test]
+    fn ignores_unsupported_exporters() {
+        assert_eq!(Exporter::parse("otlp"), (Exporter::Otlp, None));
+        assert_eq!(Exporter::parse("none"), (Exporter::None, None));
+        assert_eq!(Exporter::parse(""), (Exporter::None, None));
+        assert_eq!(
+            Exporter::parse("otlp, prometheus"),
+            (
+                Exporter::Otlp,
+                Some("Unsupported exporters are ignored: prometheus".to_owned())
+            )
+        );
+        assert_eq!(
+            Exporter::parse("console,logging"),
+            (
+                Exporter::None,
+                Some("Unsupported exporters are ignored: console, logging".to_owned())
+            )
+        );
+    }
+
+    /// The sampler and ratio chosen by the variables, and the variable warned about, if any.
+    fn sampler(sampler: &str, arg: Option<&str>) -> ((TracesSampler, f64), Option<&'static str>) {
+        let (sampler, warning) = TracesSampler::choose(sampler, arg);
+        (sampler, warning.map(|(variable, _)| variable))
+    }
+
+    #[test]
+    fn chooses_the_sampler() {
+        for (name, arg, expected) in [
+            ("always_on", None, (TracesSampler::AlwaysOn, 1.0)),
+            // the ratio is not used by other samplers
+            ("always_off", Some("0.5"), (TracesSampler::AlwaysOff, 1.0)),
+            (
+                "traceidratio",
+                Some("0.25"),
+                (TracesSampler::TraceIdRatio, 0.25),
+            ),
+            ("traceidratio", None, (TracesSampler::TraceIdRatio, 1.0)),
+            (
+                "parentbased_always_on",
+                None,
+                (TracesSampler::ParentBasedAlwaysOn, 1.0),
+            ),
+            (
+                "parentbased_always_off",
+                None,
+                (TracesSampler::ParentBasedAlwaysOff, 1.0),
+            ),
+            (
+                "parentbased_traceidratio",
+                Some("0.5"),
+                (TracesSampler::ParentBasedTraceIdRatio, 0.5),
+            ),
+        ] {
+            assert_eq!(sampler(name, arg), (expected, None), "{name}");
+        }
+    }
+
+    #[test]
+    fn ignores_unsupported_samplers_and_invalid_ratios() {
+        for unsupported in ["jaeger_remote", "parentbased_jaeger_remote", "xray"] {
+            assert_eq!(
+                sampler(unsupported, Some("0.5")),
+                ((TracesSampler::default(), 1.0), Some("OTEL_TRACES_SAMPLER"))
+            );
+        }
+        for invalid in ["1.5", "-0.1", "NaN", "half"] {
+            assert_eq!(
+                sampler("traceidratio", Some(invalid)),
+                (
+                    (TracesSampler::TraceIdRatio, 1.0),
+                    Some("OTEL_TRACES_SAMPLER_ARG")
+                )
+            );
+        }
+        // the ratio is not used by other samplers
+        assert_eq!(
+            sampler("always_on", Some("half")),
+            ((TracesSampler::AlwaysOn, 1.0), None)
+        );
+    }
+
+    /// A stand-in for the collector, which receives a signal over OTLP/HTTP at `path`.
+    fn collector(
+        path: &str,
+    ) -> anyhow::Result<(SocketAddr, tokio::sync::mpsc::UnboundedReceiver<Bytes>)> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
+        let collector = axum::Router::new().route(
+            path,
+            post(move |body: Bytes| async move {
+                let _ = sender.send(body);
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        tokio::spawn(axum::Server::from_tcp(listener)?.serve(collector.into_make_service()));
+        Ok((address, receiver))
+    }
+
+    fn contains(body: &[u8], needle: &[u8]) -> bool {
+        body.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_logs_with_the_trace_of_their_span() -> anyhow::Result<()> {
+        use opentelemetry::trace::{TraceContextExt as _, TracerProvider as _};
+
+        let (address, mut receiver) = collector("/v1/logs")?;
+        let exporter = LogExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(format!("http://{address}")),
+        )
+        .build_log_exporter()?;
+        let logger_provider = LoggerProvider::builder()
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+            .build();
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let tracer = tracer_provider.tracer("tests");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer.clone()))
+            .with(
+                LogExport {
+                    bridge: OpenTelemetryTracingBridge::new(&logger_provider),
+                    tracer: Some(tracer),
+                }
+                .with_filter(filter::filter_fn(is_exported_log)),
+            );
+
+        let span_context = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            let _entered = span.enter();
+            tracing::info!(target: "hyperactive", "logged in a span");
+            tracing::info!(target: "hyper::client", "logged by an exporter");
+            span.context().span().span_context().clone()
+        });
+        tokio::task::spawn_blocking(move || logger_provider.force_flush()).await?;
+
+        let body = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await?
+            .expect("the collector received no logs");
+        assert!(span_context.is_valid());
+        assert!(contains(&body, b"logged in a span"));
+        assert!(!contains(&body, b"logged by an exporter"));
+        assert!(contains(&body, &span_context.trace_id().to_bytes()));
+        assert!(contains(&body, &span_context.span_id().to_bytes()));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_metrics_to_the_collector() -> anyhow::Result<()> {
+        let (address, mut receiver) = collector("/v1/metrics")?;
+
+        let registry = Registry::new();
+        let counter = IntCounter::new("ndc_test_requests_total", "Number of test requests")?;
+        registry.register(Box::new(counter.clone()))?;
+        counter.inc_by(3);
+        let start_time = SystemTime::now();
+
+        let meter_provider = metrics_export(
+            &Resource::new([KeyValue::new(resource::SERVICE_NAME, "ndc-test-connector")]),
+            &format!("http://{address}"),
+            OtlpProtocol::HttpProtobuf,
+            move || (start_time, registry.gather()),
+        )
+        .map_err(|err| anyhow::anyhow!(err))?;
+        tokio::task::spawn_blocking(move || meter_provider.shutdown()).await??;
+
+        let body = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await?
+            .expect("the collector received no metrics");
+        assert!(contains(&body, b"ndc_test_requests"));
+        assert!(!contains(&body, b"ndc_test_requests_total"));
+        assert!(contains(&body, b"ndc-test-connector"));
+        assert!(contains(&body, &3.0_f64.to_le_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn derives_units_from_metric_names() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        for (name, help) in [
+            ("ndc_test_duration_seconds", "Test durations"),
+            ("ndc_test_sent_bytes_total", "Test bytes sent"),
+            ("ndc_test_requests_total", "Number of test requests"),
+        ] {
+            registry.register(Box::new(IntCounter::new(name, help)?))?;
+        }
+        let producer = PrometheusProducer {
+            gather: Box::new(move || (SystemTime::now(), registry.gather())),
+        };
+
+        let units = producer
+            .produce()?
+            .metrics
+            .into_iter()
+            .map(|metric| (metric.name.into_owned(), metric.unit.as_str().to_owned()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            units,
+            [
+                ("ndc_test_duration_seconds".to_owned(), "s".to_owned()),
+                ("ndc_test_requests".to_owned(), String::new()),
+                ("ndc_test_sent_bytes".to_owned(), "By".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+}